@@ -1,7 +1,16 @@
 use engine::{
-    body::{movegen, nnue::inference::NNUEState, position::is_quiet, search::Search, tt::TT},
+    body::{
+        movegen,
+        nnue::inference::NNUEState,
+        position::is_quiet,
+        search::{Search, SharedContext},
+        tt::TT,
+    },
     definitions,
-    uci::handler::SearchType,
+    uci::{
+        handler::{check_castling_move, SearchLimits},
+        reporter::SilentReporter,
+    },
 };
 
 use std::{
@@ -34,12 +43,19 @@ struct Parameters {
     // The number of threads to use
     threads: usize,
     // Search type for the evaluations
-    st: SearchType,
+    st: SearchLimits,
+    // Start from double fischer random positions
+    dfrc: bool,
 }
 
 impl Parameters {
-    fn new(games: usize, threads: usize, st: SearchType) -> Self {
-        Self { games, threads, st }
+    fn new(games: usize, threads: usize, st: SearchLimits, dfrc: bool) -> Self {
+        Self {
+            games,
+            threads,
+            st,
+            dfrc,
+        }
     }
 }
 
@@ -80,7 +96,7 @@ pub fn root() -> Result<(), Box<dyn Error>> {
                 panic!("Invalid depth range! {depth}, needs to be between [1, 100]")
             }
 
-            SearchType::Depth(depth)
+            SearchLimits::depth(depth)
         }
         "nodes" => {
             println!("What nodes would you like to use? [1, 100M]");
@@ -92,20 +108,33 @@ pub fn root() -> Result<(), Box<dyn Error>> {
                 panic!("Invalid nodes range! {nodes}, needs to be between [1, 100M]")
             }
 
-            SearchType::Nodes(nodes)
+            SearchLimits::nodes(nodes)
         }
         _ => {
             panic!("Invalid search type! {inp_st}")
         }
     };
 
+    // Get the opening type
+    println!("Would you like to start from DFRC positions? [y, n]");
+    let mut inp_dfrc = String::new();
+    stdin().read_line(&mut inp_dfrc).unwrap();
+
+    let dfrc = match inp_dfrc.trim().to_lowercase().as_str() {
+        "y" => true,
+        "n" => false,
+        _ => {
+            panic!("Invalid answer! {inp_dfrc}")
+        }
+    };
+
     // Let the user confirm the parameters
-    let params = Parameters::new(games, threads, st);
+    let params = Parameters::new(games, threads, st, dfrc);
     println!(
-        "\n{GREEN}Confirmed parameters: {DEFAULT}[games: {WHITE}{}{DEFAULT}, threads: {WHITE}{}{DEFAULT}, search type: {WHITE}{:?}{DEFAULT}]",
-        params.games, params.threads, params.st
+        "\n{GREEN}Confirmed parameters: {DEFAULT}[games: {WHITE}{}{DEFAULT}, threads: {WHITE}{}{DEFAULT}, search type: {WHITE}{:?}{DEFAULT}, dfrc: {WHITE}{}{DEFAULT}]",
+        params.games, params.threads, params.st, params.dfrc
     );
-    if params.games % params.threads != 0 {
+    if !params.games.is_multiple_of(params.threads) {
         println!("{ORANGE}WARNING: {DEFAULT}The number of games is not divisible by the number of threads!");
     }
     println!("Press enter to continue...");
@@ -147,11 +176,13 @@ fn generate_main(params: Parameters) {
 
 fn generate_thread(id: usize, data_dir: &Path, options: &Parameters) {
     let tt = TT::new(16);
+    let shared = SharedContext::new();
     let nnue = NNUEState::from_board(&Board::default());
 
     // not implemented properly
     let history = engine::body::history::History::new();
-    let mut search = Search::new(&tt, &nnue, &history, &vec![]);
+    let mut search = Search::new(&tt, &shared, &nnue, &history, &vec![]);
+    search.reporter = Box::new(SilentReporter);
     let rng = fastrand::Rng::new();
 
     let mut board;
@@ -191,8 +222,13 @@ fn generate_thread(id: usize, data_dir: &Path, options: &Parameters) {
 
         // Reset everything from previous game
         output_buffer.flush().unwrap();
-        board = Board::default();
+        board = if options.dfrc {
+            Board::double_chess960_startpos(rng.u32(..960), rng.u32(..960))
+        } else {
+            Board::default()
+        };
         search.game_reset();
+        search.info.chess960 = options.dfrc;
         hashes = vec![];
 
         // Play a new game
@@ -211,8 +247,8 @@ fn generate_thread(id: usize, data_dir: &Path, options: &Parameters) {
         search.nnue.refresh(&board);
 
         // ... make sure that the exit isn't absurd
-        let (score, _) = search.data_search(&board, SearchType::Depth(8));
-        if score.abs() > 1000 {
+        let result = search.iterative_deepening::<true>(&board, SearchLimits::depth(8));
+        if result.score.abs() > 1000 {
             continue 'main;
         }
 
@@ -232,7 +268,10 @@ fn generate_thread(id: usize, data_dir: &Path, options: &Parameters) {
 
             search.go_reset();
             //search.tt.age();
-            let (mut score, best_move) = search.data_search(&board, options.st);
+            let result = search.iterative_deepening::<true>(&board, options.st);
+            let mut score = result.score;
+            // The PV holds castling in UCI notation, the board wants king takes rook
+            let best_move = check_castling_move(&board, result.best_move.unwrap(), options.dfrc);
 
             // filter noisy positions
             let not_in_check = board.checkers().is_empty();
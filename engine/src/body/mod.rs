@@ -2,8 +2,10 @@ pub mod history;
 mod lmr;
 pub mod movegen;
 pub mod nnue;
+pub mod perft;
 pub mod position;
 pub mod pv_table;
 pub mod search;
 mod stat_vec;
+pub mod thread_pool;
 pub mod tt;
@@ -28,10 +28,22 @@ struct Parameters {
 
 // the model is read from binary files at compile time
 static MODEL: Parameters = Parameters {
-    feature_weights: unsafe { std::mem::transmute(*include_bytes!("net/feature_weights.bin")) },
-    feature_bias: unsafe { std::mem::transmute(*include_bytes!("net/feature_bias.bin")) },
-    output_weights: unsafe { std::mem::transmute(*include_bytes!("net/output_weights.bin")) },
-    output_bias: unsafe { std::mem::transmute(*include_bytes!("net/output_bias.bin")) },
+    feature_weights: unsafe {
+        std::mem::transmute::<[u8; FEATURES * HIDDEN * 2], [i16; FEATURES * HIDDEN]>(
+            *include_bytes!("net/feature_weights.bin"),
+        )
+    },
+    feature_bias: unsafe {
+        std::mem::transmute::<[u8; HIDDEN * 2], [i16; HIDDEN]>(*include_bytes!(
+            "net/feature_bias.bin"
+        ))
+    },
+    output_weights: unsafe {
+        std::mem::transmute::<[u8; HIDDEN * 4], [i16; HIDDEN * 2]>(*include_bytes!(
+            "net/output_weights.bin"
+        ))
+    },
+    output_bias: i16::from_ne_bytes(*include_bytes!("net/output_bias.bin")),
 };
 
 #[derive(Clone)]
@@ -197,7 +209,12 @@ fn weight_column_index(sq: Square, piece: Piece, color: Color) -> (usize, usize)
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::body::{movegen, position::play_move, search::Search, tt::TT};
+    use crate::body::{
+        movegen,
+        position::play_move,
+        search::{Search, SharedContext},
+        tt::TT,
+    };
 
     #[test]
     fn nnue_update_feature() {
@@ -216,10 +233,11 @@ mod tests {
     fn nnue_moves() {
         let board = Board::default();
         let tt = TT::new(16);
+        let shared = SharedContext::new();
         let nnue = NNUEState::from_board(&board);
         let history = crate::body::history::History::new();
 
-        let mut search = Search::new(&tt, &nnue, &history, &vec![board.hash()]);
+        let mut search = Search::new(&tt, &shared, &nnue, &history, &vec![board.hash()]);
 
         let moves = movegen::all_moves(&search, &board, None, 0);
         let initial_white = search.nnue.accumulators[0].white;
@@ -258,10 +276,11 @@ mod tests {
         for fen in fens {
             let mut board = Board::from_fen(fen, false).unwrap();
             let tt = TT::new(16);
+            let shared = SharedContext::new();
             let nnue = NNUEState::from_board(&board);
             let history = crate::body::history::History::new();
 
-            let mut search = Search::new(&tt, &nnue, &history, &vec![board.hash()]);
+            let mut search = Search::new(&tt, &shared, &nnue, &history, &vec![board.hash()]);
             search.nnue.refresh(&board);
             let moves = movegen::all_moves(&search, &board, None, 0);
 
@@ -0,0 +1,254 @@
+use super::{movegen, nnue::inference::NNUEState, position::play_move};
+use crate::uci::handler::reverse_castling_move;
+
+use cozy_chess::{Board, Move};
+use std::time::Instant;
+
+// Counts the leaf nodes of the legal move tree, with bulk counting at the last ply.
+#[must_use]
+pub fn perft(board: &Board, depth: usize) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let mut nodes = 0;
+    board.generate_moves(|moves| {
+        if depth == 1 {
+            nodes += moves.len() as u64;
+        } else {
+            for mv in moves {
+                let mut new_b = board.clone();
+                new_b.play_unchecked(mv);
+                nodes += perft(&new_b, depth - 1);
+            }
+        }
+        false
+    });
+
+    nodes
+}
+
+// Same as perft, but every move goes through `play_move` so the NNUE accumulators
+// are updated on the way down and popped on the way up, just like in search.
+#[must_use]
+pub fn perft_nnue(board: &Board, nnue: &mut Box<NNUEState>, depth: usize) -> u64 {
+    if depth == 0 {
+        return 1;
+    }
+
+    let mut nodes = 0;
+    for mv in movegen::pure_moves(board) {
+        let mut new_b = board.clone();
+        play_move(&mut new_b, nnue, mv);
+        nodes += perft_nnue(&new_b, nnue, depth - 1);
+        nnue.pop();
+    }
+
+    nodes
+}
+
+// Node counts per root move, with moves in UCI notation.
+#[must_use]
+pub fn divide(board: &Board, depth: usize, chess960: bool) -> Vec<(Move, u64)> {
+    movegen::pure_moves(board)
+        .into_iter()
+        .map(|mv| {
+            let mut new_b = board.clone();
+            new_b.play_unchecked(mv);
+            (
+                reverse_castling_move(board, mv, chess960),
+                perft(&new_b, depth.max(1) - 1),
+            )
+        })
+        .collect()
+}
+
+pub fn run(board: &Board, depth: usize, split: bool, chess960: bool) {
+    let timer = Instant::now();
+
+    let nodes = if split {
+        let mut total = 0;
+        for (mv, nodes) in divide(board, depth, chess960) {
+            println!("{mv}: {nodes}");
+            total += nodes;
+        }
+        println!();
+        total
+    } else {
+        perft(board, depth)
+    };
+
+    let time = timer.elapsed().as_millis();
+    println!(
+        "Perft {depth}: {nodes} nodes {time} ms {} nps",
+        nodes * 1000 / (time as u64).max(1)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (fen, depth, nodes) from https://www.chessprogramming.org/Perft_Results
+    const POSITIONS: [(&str, usize, u64); 6] = [
+        (
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            4,
+            197_281,
+        ),
+        (
+            "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1",
+            3,
+            97_862,
+        ),
+        ("8/2p5/3p4/KP5r/1R3p1k/8/4P1P1/8 w - - 0 1", 5, 674_624),
+        (
+            "r3k2r/Pppp1ppp/1b3nbN/nP6/BBP1P3/q4N2/Pp1P2PP/R2Q1RK1 w kq - 0 1",
+            3,
+            9_467,
+        ),
+        (
+            "rnbq1k1r/pp1Pbppp/2p5/8/2B5/8/PPP1NnPP/RNBQK2R w KQ - 1 8",
+            3,
+            62_379,
+        ),
+        (
+            "r4rk1/1pp1qppp/p1np1n2/2b1p1B1/2B1P1b1/P1NP1N2/1PP1QPPP/R4RK1 w - - 0 10",
+            3,
+            89_890,
+        ),
+    ];
+
+    // Shredder-FEN (fen, depth, nodes) from https://www.chessprogramming.org/Chess960_Perft_Results
+    const FRC_POSITIONS: [(&str, usize, u64); 5] = [
+        (
+            "bqnb1rkr/pp3ppp/3ppn2/2p5/5P2/P2P4/NPP1P1PP/BQ1BNRKR w HFhf - 2 9",
+            4,
+            326_672,
+        ),
+        (
+            "2nnrbkr/p1qppppp/8/1ppb4/6PP/3PP3/PPP2P2/BQNNRBKR w HEhe - 1 9",
+            3,
+            18_002,
+        ),
+        (
+            "b1q1rrkb/pppppppp/3nn3/8/P7/1PPP4/4PPPP/BQNNRKRB w GE - 1 9",
+            3,
+            10_471,
+        ),
+        (
+            "qbbnnrkr/2pp2pp/p7/1p2pp2/8/P3PP2/1PPP1KPP/QBBNNR1R w hf - 0 9",
+            3,
+            13_440,
+        ),
+        (
+            "1nbbnrkr/p1p1ppp1/3p4/1p3P1p/3Pq2P/8/PPP1P1P1/QNBBNRKR w HFhf - 0 9",
+            3,
+            31_058,
+        ),
+    ];
+
+    // Perft through `play_move` which also checks that the incrementally updated
+    // accumulator matches a fresh one in every node along the way.
+    fn perft_verified(
+        board: &Board,
+        nnue: &mut Box<NNUEState>,
+        scratch: &mut NNUEState,
+        depth: usize,
+    ) -> u64 {
+        scratch.refresh(board);
+        assert_eq!(
+            nnue.accumulators[nnue.current_acc], scratch.accumulators[0],
+            "{board}"
+        );
+
+        if depth == 0 {
+            return 1;
+        }
+
+        let mut nodes = 0;
+        for mv in movegen::pure_moves(board) {
+            let mut new_b = board.clone();
+            play_move(&mut new_b, nnue, mv);
+            nodes += perft_verified(&new_b, nnue, scratch, depth - 1);
+            nnue.pop();
+        }
+
+        nodes
+    }
+
+    #[test]
+    fn perft_positions() {
+        for (fen, depth, nodes) in POSITIONS {
+            let board = Board::from_fen(fen, false).unwrap();
+            assert_eq!(perft(&board, depth), nodes, "{fen}");
+        }
+    }
+
+    #[test]
+    fn perft_frc_positions() {
+        for (fen, depth, nodes) in FRC_POSITIONS {
+            let board = Board::from_fen(fen, true).unwrap();
+            assert_eq!(perft(&board, depth), nodes, "{fen}");
+        }
+    }
+
+    #[test]
+    fn divide_sums_to_perft() {
+        for (fen, depth, nodes) in POSITIONS {
+            let board = Board::from_fen(fen, false).unwrap();
+            let split = divide(&board, depth, false);
+
+            assert_eq!(split.len(), movegen::pure_moves(&board).len());
+            assert_eq!(split.iter().map(|(_, n)| n).sum::<u64>(), nodes, "{fen}");
+        }
+
+        // Castling is reported in standard notation
+        let board = Board::from_fen(POSITIONS[1].0, false).unwrap();
+        let moves: Vec<String> = divide(&board, 1, false)
+            .iter()
+            .map(|(mv, _)| mv.to_string())
+            .collect();
+        assert!(moves.contains(&"e1g1".to_string()));
+        assert!(moves.contains(&"e1c1".to_string()));
+
+        // ... and as king takes rook in Chess960
+        let moves: Vec<String> = divide(&board, 1, true)
+            .iter()
+            .map(|(mv, _)| mv.to_string())
+            .collect();
+        assert!(moves.contains(&"e1h1".to_string()));
+        assert!(moves.contains(&"e1a1".to_string()));
+    }
+
+    #[test]
+    fn perft_play_move() {
+        for (fen, depth, _) in POSITIONS {
+            let board = Board::from_fen(fen, false).unwrap();
+            let mut nnue = NNUEState::from_board(&board);
+            let depth = depth.min(2);
+
+            assert_eq!(
+                perft_nnue(&board, &mut nnue, depth),
+                perft(&board, depth),
+                "{fen}"
+            );
+            assert_eq!(nnue.current_acc, 0);
+        }
+    }
+
+    #[test]
+    fn perft_nnue_integrity() {
+        let standard = POSITIONS.iter().map(|&(fen, _, _)| (fen, false));
+        let frc = FRC_POSITIONS.iter().map(|&(fen, _, _)| (fen, true));
+
+        for (fen, shredder) in standard.chain(frc) {
+            let board = Board::from_fen(fen, shredder).unwrap();
+            let mut nnue = NNUEState::from_board(&board);
+            let mut scratch = NNUEState::from_board(&board);
+
+            let nodes = perft_verified(&board, &mut nnue, &mut scratch, 2);
+            assert_eq!(nodes, perft(&board, 2), "{fen}");
+        }
+    }
+}
@@ -28,6 +28,8 @@ pub fn play_move(board: &mut Board, nnue: &mut Box<NNUEState>, mv: Move) {
     }
 
     // Castling
+    // The king and rook always land on the same squares, whatever files they
+    // started on, so this holds for Chess960 as well.
     if Some(stm) == board.color_on(mv.to) {
         let rank = Rank::First.relative_to(stm);
         // King side
@@ -2,6 +2,7 @@ use crate::definitions::MAX_PLY;
 use crate::uci::handler::reverse_castling_move;
 use cozy_chess::{Board, Move};
 
+#[derive(Clone)]
 pub struct PVTable {
     pub length: usize,
     pub table: [Option<Move>; MAX_PLY],
@@ -15,8 +16,8 @@ impl PVTable {
         }
     }
 
-    pub fn store(&mut self, board: &Board, mv: Move, old: &Self) {
-        let mv = reverse_castling_move(board, mv);
+    pub fn store(&mut self, board: &Board, mv: Move, old: &Self, chess960: bool) {
+        let mv = reverse_castling_move(board, mv, chess960);
         self.table[0] = Some(mv);
         self.table[1..=old.length].copy_from_slice(&old.table[..old.length]);
         self.length = old.length + 1;
@@ -26,16 +27,6 @@ impl PVTable {
         &self.table[..self.length]
     }
 
-    pub fn pv_string(&self) -> String {
-        let mut pv = String::new();
-        for &mv in self.moves() {
-            pv.push(' ');
-            pv.push_str(mv.unwrap().to_string().as_str());
-        }
-
-        pv
-    }
-
     pub fn best_move(&self) -> Option<Move> {
         self.table[0]
     }
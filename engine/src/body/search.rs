@@ -11,16 +11,20 @@ use super::{
 };
 
 use crate::definitions::*;
-use crate::uci::handler::SearchType;
+use crate::uci::{
+    handler::{check_castling_move, reverse_castling_move, SearchLimits},
+    reporter::{IterationReport, Reporter, UciReporter},
+    timeman::{SoftTime, TimeOptions, TimeSource, WallClock},
+};
 
 use cozy_chess::{BitBoard, Board, Color, GameStatus, Move, Piece};
 use once_cell::sync::Lazy;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::time::Instant;
+use std::cmp::Reverse;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
 static LMR: Lazy<LMRTable> = Lazy::new(LMRTable::new);
-static STOP: AtomicBool = AtomicBool::new(false);
-static NODES: AtomicU64 = AtomicU64::new(0);
 
 const RFP_MARGIN: i32 = 75;
 const LMP_TABLE: [usize; 4] = [0, 5, 8, 18];
@@ -28,6 +32,13 @@ const FP_COEFFICIENT: i32 = 100;
 const FP_MARGIN: i32 = 75;
 const FP_DEPTH: i32 = 6;
 
+// Helper threads skip depths in these patterns, so they don't all search the same one
+const SKIP_SIZE: [usize; 20] = [1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4, 4, 4];
+const SKIP_PHASE: [usize; 20] = [0, 1, 0, 1, 2, 3, 0, 1, 2, 3, 4, 5, 0, 1, 2, 3, 4, 5, 6, 7];
+
+// Milliseconds into a search before the GUI hears about anything but finished iterations
+const INFO_DELAY: u64 = 3000;
+
 pub struct StackEntry {
     pub eval: i32,
 }
@@ -39,10 +50,23 @@ impl Default for StackEntry {
 }
 
 pub struct SearchInfo {
-    pub search_type: SearchType,
-    pub timer: Option<Instant>,
-    pub base_optimum: Option<u64>,
+    // 0 for the main thread
+    pub thread_id: usize,
+    pub limits: SearchLimits,
+    pub clock: Arc<dyn TimeSource>,
+    // The clock reading when our time started running
+    pub timer: Option<u64>,
+    // The clock reading when the search started, for the info output
+    pub search_start: u64,
     pub max_time: Option<u64>,
+    pub pondering: bool,
+    // The Ponder option, expected ponderhits buy some extra time
+    pub ponder: bool,
+    pub time_options: TimeOptions,
+    // Root fail lows of the main thread during the current iteration
+    pub fail_lows: usize,
+    // The iteration being searched, before any extension or reduction
+    pub depth: usize,
     pub nodes: u64,
     prev_nodes: u64,
     pub node_table: [[u64; 64]; 64],
@@ -51,15 +75,26 @@ pub struct SearchInfo {
     pub killers: [[Option<Move>; 2]; MAX_PLY],
     pub history: History,
     pub stack: [StackEntry; MAX_PLY],
+    pub multipv: usize,
+    pub excluded_moves: Vec<Move>,
+    pub root_moves: Vec<Move>,
+    pub chess960: bool,
 }
 
 impl SearchInfo {
     pub fn new() -> Self {
         SearchInfo {
-            search_type: SearchType::Depth(0),
+            thread_id: 0,
+            limits: SearchLimits::default(),
+            clock: Arc::new(WallClock::new()),
             timer: None,
-            base_optimum: None,
+            search_start: 0,
             max_time: None,
+            pondering: false,
+            ponder: false,
+            time_options: TimeOptions::default(),
+            fail_lows: 0,
+            depth: 0,
             nodes: 0,
             prev_nodes: 0,
             node_table: [[0; 64]; 64],
@@ -68,8 +103,29 @@ impl SearchInfo {
             killers: [[None; 2]; MAX_PLY],
             history: History::new(),
             stack: std::array::from_fn(|_| StackEntry::default()),
+            multipv: 1,
+            excluded_moves: vec![],
+            root_moves: vec![],
+            chess960: false,
         }
     }
+
+    // Forgets what only mattered to the previous search, history and killers carry over
+    pub fn new_search(&mut self) {
+        self.limits = SearchLimits::default();
+        self.timer = None;
+        self.search_start = 0;
+        self.max_time = None;
+        self.pondering = false;
+        self.fail_lows = 0;
+        self.depth = 0;
+        self.nodes = 0;
+        self.prev_nodes = 0;
+        self.node_table = [[0; 64]; 64];
+        self.seldepth = 0;
+        self.excluded_moves.clear();
+        self.root_moves.clear();
+    }
 }
 
 impl Default for SearchInfo {
@@ -78,44 +134,195 @@ impl Default for SearchInfo {
     }
 }
 
-pub fn store_stop(stop: bool) {
-    STOP.store(stop, Ordering::SeqCst);
+// A score the way the GUI sees it. Mates are counted in moves, negative when getting mated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Score {
+    Cp(i32),
+    Mate(i32),
 }
 
-pub fn load_stop() -> bool {
-    STOP.load(Ordering::SeqCst)
+impl From<i32> for Score {
+    fn from(score: i32) -> Self {
+        if score >= MATE_IN {
+            Score::Mate(((MATE - score) / 2) + ((MATE - score) & 1))
+        } else if score <= -MATE_IN {
+            Score::Mate(-(((MATE + score) / 2) + ((MATE + score) & 1)))
+        } else {
+            Score::Cp(score)
+        }
+    }
 }
 
-pub fn clear_nodes() {
-    NODES.store(0, Ordering::SeqCst);
+impl fmt::Display for Score {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Score::Cp(cp) => write!(f, "cp {cp}"),
+            Score::Mate(moves) => write!(f, "mate {moves}"),
+        }
+    }
 }
 
-fn add_nodes(nodes: u64) {
-    NODES.fetch_add(nodes, Ordering::SeqCst);
+// Everything a caller may want to know once a search is over, with moves in UCI notation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchResult {
+    // None if there was no legal move, or a helper thread didn't finish depth 1
+    pub best_move: Option<Move>,
+    pub ponder: Option<Move>,
+    pub pv: Vec<Move>,
+    // Internal score from the side to move, see `Score` for the GUI form
+    pub score: i32,
+    // Last fully searched depth
+    pub depth: usize,
+    pub seldepth: usize,
+    pub nodes: u64,
+    // milliseconds
+    pub time: u128,
+    // Nodes spent on each root move, most searched first
+    pub root_nodes: Vec<(Move, u64)>,
+}
+
+// What one thread found in its last completed iteration, moves in UCI notation
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadResult {
+    pub thread_id: usize,
+    pub pv: Vec<Move>,
+    pub score: i32,
+    pub depth: usize,
+    pub seldepth: usize,
+}
+
+// The thread whose best move gets the most votes. Every thread votes for its own move,
+// deeper iterations and better scores weigh more. A proven mate beats any vote.
+pub fn vote(results: &[ThreadResult]) -> Option<&ThreadResult> {
+    let results: Vec<&ThreadResult> = results.iter().filter(|r| !r.pv.is_empty()).collect();
+    let min_score = results.iter().map(|r| r.score).min()?;
+
+    if let Some(mate) = results
+        .iter()
+        .filter(|r| r.score >= MATE_IN)
+        .max_by_key(|r| (r.score, Reverse(r.thread_id)))
+    {
+        return Some(mate);
+    }
+
+    let votes = |mv: Move| -> i64 {
+        results
+            .iter()
+            .filter(|r| r.pv[0] == mv)
+            .map(|r| i64::from(r.score - min_score + 14) * r.depth as i64)
+            .sum()
+    };
+
+    // Ties go to the deeper thread, then to the main thread
+    results
+        .iter()
+        .copied()
+        .max_by_key(|r| (votes(r.pv[0]), r.depth, Reverse(r.thread_id)))
 }
 
-fn load_nodes() -> u64 {
-    NODES.load(Ordering::SeqCst)
+// The state shared by all threads of one search group. Each group, be it the UCI
+// engine, a datagen worker or a test, owns its own so they can't stop each other.
+#[derive(Default)]
+pub struct SharedContext {
+    stop: AtomicBool,
+    nodes: AtomicU64,
+    ponder: AtomicBool,
+    // The deepest iteration any thread completed
+    completed_depth: AtomicUsize,
+    // The latest completed iteration of every thread
+    results: Mutex<Vec<ThreadResult>>,
+}
+
+impl SharedContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn store_stop(&self, stop: bool) {
+        self.stop.store(stop, Ordering::SeqCst);
+    }
+
+    pub fn load_stop(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    pub fn store_ponder(&self, ponder: bool) {
+        self.ponder.store(ponder, Ordering::SeqCst);
+    }
+
+    pub fn load_ponder(&self) -> bool {
+        self.ponder.load(Ordering::SeqCst)
+    }
+
+    // Forgets the nodes and iterations of the previous search
+    pub fn clear_search(&self) {
+        self.nodes.store(0, Ordering::SeqCst);
+        self.completed_depth.store(0, Ordering::SeqCst);
+        self.results.lock().unwrap().clear();
+    }
+
+    fn add_nodes(&self, nodes: u64) {
+        self.nodes.fetch_add(nodes, Ordering::SeqCst);
+    }
+
+    pub fn load_nodes(&self) -> u64 {
+        self.nodes.load(Ordering::SeqCst)
+    }
+
+    pub fn load_completed_depth(&self) -> usize {
+        self.completed_depth.load(Ordering::SeqCst)
+    }
+
+    fn complete_iteration(&self, result: ThreadResult) {
+        let mut results = self.results.lock().unwrap();
+
+        // Once stopped the vote is on, helpers finishing now come too late
+        if result.thread_id != 0 && self.load_stop() {
+            return;
+        }
+
+        self.completed_depth
+            .fetch_max(result.depth, Ordering::SeqCst);
+        match results.iter_mut().find(|r| r.thread_id == result.thread_id) {
+            Some(entry) => *entry = result,
+            None => results.push(result),
+        }
+    }
+
+    pub fn thread_results(&self) -> Vec<ThreadResult> {
+        self.results.lock().unwrap().clone()
+    }
+
+    // The deepest any thread went in its latest iteration
+    pub fn seldepth(&self) -> usize {
+        let results = self.results.lock().unwrap();
+        results.iter().map(|r| r.seldepth).max().unwrap_or(0)
+    }
 }
 
 pub struct Search<'a> {
     pub nnue: Box<NNUEState>,
     pub tt: &'a TT,
+    pub shared: &'a SharedContext,
     pub info: SearchInfo,
+    pub reporter: Box<dyn Reporter + Send + 'a>,
 }
 
 impl<'a> Search<'a> {
     #[allow(clippy::borrowed_box, clippy::ptr_arg)]
     pub fn new(
         tt: &'a TT,
+        shared: &'a SharedContext,
         nnue: &Box<NNUEState>,
         history: &History,
         game_history: &Vec<u64>,
     ) -> Self {
         let mut s = Search {
             tt,
+            shared,
             nnue: nnue.clone(),
             info: SearchInfo::new(),
+            reporter: Box::new(UciReporter),
         };
 
         s.info.history = history.clone();
@@ -156,23 +363,19 @@ impl<'a> Search<'a> {
         mut depth: i32,
         ply: usize,
     ) -> i32 {
-        // Every 1024 nodes, check if it's time to stop
-        if let (Some(timer), Some(max)) = (self.info.timer, self.info.max_time) {
-            if main_thread
-                && self.info.nodes % 1024 == 0
-                && timer.elapsed().as_millis() as u64 >= max
-            {
-                store_stop(true);
-            }
+        if main_thread && self.info.nodes.is_multiple_of(1024) {
+            self.check_ponderhit();
         }
 
-        if load_stop() && ply > 0 {
+        self.check_time();
+        self.check_node_limit();
+
+        if self.stopped() && ply > 0 {
             return 0;
         }
 
-        if self.info.nodes % 2048 == 0 {
-            add_nodes(self.info.nodes - self.info.prev_nodes);
-            self.info.prev_nodes = self.info.nodes;
+        if self.info.nodes.is_multiple_of(2048) {
+            self.flush_nodes();
         }
 
         let stm = board.side_to_move();
@@ -217,11 +420,11 @@ impl<'a> Search<'a> {
         }
 
         let tt_entry = self.tt.probe(hash_key);
-        let tt_hit = tt_entry.key == hash_key as u16;
-        let tt_score = score_from_tt(tt_entry.score, ply) as i32;
+        let tt_hit = tt_entry.is_some();
+        let tt_score = tt_entry.map_or(NONE, |entry| score_from_tt(entry.score, ply) as i32);
         let mut tt_move: Option<Move> = None;
 
-        if tt_hit && u64::from(tt_entry) != 0 {
+        if let Some(tt_entry) = tt_entry {
             tt_move = Some(PackedMove::unpack(tt_entry.mv));
 
             if !PV && i32::from(tt_entry.depth) >= depth {
@@ -248,8 +451,12 @@ impl<'a> Search<'a> {
             self.nnue.evaluate(stm)
         };
 
+        // Pruning can't prove mates, and reductions put them out of reach. A mate search
+        // looks at every move at full depth, so a mate in N is found by depth 2N - 1.
+        let mate_search = self.info.limits.mate.is_some();
+
         // Internal Iterative Reduction (IIR)
-        if depth >= 3 && !tt_hit {
+        if depth >= 3 && !tt_hit && !mate_search {
             depth -= 1
         }
 
@@ -262,7 +469,7 @@ impl<'a> Search<'a> {
             improving = !in_check && eval > self.info.stack[ply - 2].eval;
         }
 
-        if !PV && !in_check {
+        if !PV && !in_check && !mate_search {
             // Reverse Futility Pruning (RFP)
             // If static eval plus a margin can beat beta, then we can safely prune this node.
             // The margin is multiplied by depth to make it harder to prune at higher depths
@@ -316,6 +523,24 @@ impl<'a> Search<'a> {
         depth += i32::from(in_check);
 
         while let Some(mv) = picker.pick_move() {
+            if root {
+                // Searchmoves
+                // Only the requested root moves are searched, if any were given
+                if !self.info.root_moves.is_empty() && !self.info.root_moves.contains(&mv) {
+                    continue;
+                }
+
+                // MultiPV
+                // Root moves that already head a previous PV line are skipped
+                if self.info.excluded_moves.contains(&reverse_castling_move(
+                    board,
+                    mv,
+                    self.info.chess960,
+                )) {
+                    continue;
+                }
+            }
+
             let is_quiet = is_quiet(board, mv);
             let lmr_reduction = LMR.reduction(depth, moves_played.max(1));
             let lmr_depth = 0.max(depth - lmr_reduction);
@@ -323,7 +548,7 @@ impl<'a> Search<'a> {
             if is_quiet {
                 quiets_checked += 1;
 
-                if !PV && !in_check && best_score > TB_LOSS_IN_PLY {
+                if !PV && !in_check && !mate_search && best_score > TB_LOSS_IN_PLY {
                     // Late Move Pruning (LMP)
                     // If we have searched too many moves, we stop searching here
                     if quiets_checked >= quiets_to_check {
@@ -345,6 +570,13 @@ impl<'a> Search<'a> {
             play_move(&mut new_b, &mut self.nnue, mv);
 
             moves_played += 1;
+
+            if root && main_thread && self.elapsed(self.info.search_start) >= INFO_DELAY {
+                let mv = reverse_castling_move(board, mv, self.info.chess960);
+                self.reporter
+                    .current_move(self.info.depth, mv, moves_played as usize);
+            }
+
             self.info.game_history.push(board.hash());
             self.info.nodes += 1;
             let previous_nodes = self.info.nodes;
@@ -366,7 +598,7 @@ impl<'a> Search<'a> {
                 // Assuming our move ordering is good, later moves will be worse
                 // and can be searched with a reduced depth, if they beat alpha
                 // we do a full re-search.
-                let r = if depth >= 3 && moves_played > lmr_threshold {
+                let r = if depth >= 3 && moves_played > lmr_threshold && !mate_search {
                     // Probe LMR table (src/lmr.rs)
                     let mut r = LMR.reduction(depth, moves_played);
 
@@ -429,6 +661,11 @@ impl<'a> Search<'a> {
                     self.info.nodes - previous_nodes;
             }
 
+            // The rest of the moves would only return right away
+            if self.stopped() && ply > 0 {
+                return 0;
+            }
+
             if score <= best_score {
                 continue;
             }
@@ -440,7 +677,7 @@ impl<'a> Search<'a> {
             // New best move
             alpha = score;
             best_move = Some(mv);
-            pv.store(board, mv, &old_pv);
+            pv.store(board, mv, &old_pv, self.info.chess960);
 
             // Fail-high
             if score >= beta {
@@ -477,7 +714,11 @@ impl<'a> Search<'a> {
 
         debug_assert!((-INFINITY..=INFINITY).contains(&best_score));
 
-        if !load_stop() {
+        // Root scores over a restricted move list are not the true score of the position
+        let excluded_root =
+            root && !(self.info.excluded_moves.is_empty() && self.info.root_moves.is_empty());
+
+        if !self.stopped() && !excluded_root {
             self.tt.store(
                 hash_key,
                 best_move,
@@ -499,20 +740,15 @@ impl<'a> Search<'a> {
         beta: i32,
         ply: usize,
     ) -> i32 {
-        if let (Some(timer), Some(max)) = (self.info.timer, self.info.max_time) {
-            if self.info.nodes % 1024 == 0 && timer.elapsed().as_millis() as u64 >= max {
-                store_stop(true);
-                return 0;
-            }
-        }
+        self.check_time();
+        self.check_node_limit();
 
-        if load_stop() && ply > 0 {
+        if self.stopped() && ply > 0 {
             return 0;
         }
 
-        if self.info.nodes % 2048 == 0 {
-            add_nodes(self.info.nodes - self.info.prev_nodes);
-            self.info.prev_nodes = self.info.nodes;
+        if self.info.nodes.is_multiple_of(2048) {
+            self.flush_nodes();
         }
 
         let stm = board.side_to_move();
@@ -531,11 +767,9 @@ impl<'a> Search<'a> {
             return stand_pat;
         }
 
-        let tt_entry = self.tt.probe(hash_key);
-        let tt_hit = tt_entry.key == hash_key as u16;
         let mut tt_move: Option<Move> = None;
 
-        if tt_hit && u64::from(tt_entry) != 0 {
+        if let Some(tt_entry) = self.tt.probe(hash_key) {
             tt_move = Some(PackedMove::unpack(tt_entry.mv));
 
             if !PV {
@@ -567,6 +801,10 @@ impl<'a> Search<'a> {
 
             self.nnue.pop();
 
+            if self.stopped() && ply > 0 {
+                return 0;
+            }
+
             if score <= best_score {
                 continue;
             }
@@ -591,7 +829,7 @@ impl<'a> Search<'a> {
             TTFlag::UpperBound
         };
 
-        if !load_stop() {
+        if !self.stopped() {
             self.tt
                 .store(hash_key, best_move, best_score as i16, 0, flag, ply);
         }
@@ -602,126 +840,353 @@ impl<'a> Search<'a> {
     pub fn iterative_deepening<const MAIN_THREAD: bool>(
         &mut self,
         board: &Board,
-        st: SearchType,
-        pretty: bool,
-    ) {
-        let depth: usize;
-        let mut opt_time: Option<u64> = None;
-        let mut goal_nodes: Option<u64> = None;
-
-        match st {
-            SearchType::Time(opt, max) => {
-                depth = MAX_PLY;
-                self.info.timer = Some(Instant::now());
-                self.info.max_time = Some(max);
-                self.info.base_optimum = Some(opt);
-                opt_time = Some(opt);
-            }
-            SearchType::Infinite => {
-                depth = MAX_PLY;
-            }
-            SearchType::Depth(d) => depth = d.min(MAX_PLY),
-            SearchType::Nodes(n) => {
-                depth = MAX_PLY;
-                goal_nodes = Some(n);
+        limits: SearchLimits,
+    ) -> SearchResult {
+        let depth = limits.depth.unwrap_or(MAX_PLY).clamp(1, MAX_PLY);
+        let goal_nodes = limits.nodes;
+        let goal_mate = limits.mate;
+        let time_bounds = limits.time_bounds(self.info.ponder, &self.info.time_options);
+        let mut soft_time =
+            time_bounds.map(|(opt, max)| SoftTime::new(opt, max, self.info.time_options));
+        self.info.limits = limits;
+
+        if let Some((_, max)) = time_bounds {
+            // While pondering the clock only starts on ponderhit
+            if !self.info.pondering {
+                self.info.timer = Some(self.start());
             }
-        };
+            self.info.max_time = Some(max);
+        }
+
+        let info_timer = self.start();
+        self.info.search_start = info_timer;
 
         // SMP - might clean up in the future
         if !MAIN_THREAD {
-            let mut s = 0;
-            let mut phony_bm: Option<Move> = None;
+            let mut score = 0;
+            let mut best_move: Option<Move> = None;
+            let mut completed = (PVTable::new(), 0, 0);
+            let skip = (self.info.thread_id.max(1) - 1) % SKIP_SIZE.len();
 
             for d in 1..=depth {
-                s = self.aspiration_window(
-                    false,
-                    board,
-                    &mut PVTable::new(),
-                    s,
-                    d as i32,
-                    &mut phony_bm,
-                );
+                // Spread out over the depths, and leave those someone else already finished
+                if !((d + SKIP_PHASE[skip]) / SKIP_SIZE[skip]).is_multiple_of(2)
+                    || d <= self.shared.load_completed_depth()
+                {
+                    continue;
+                }
 
-                if load_stop() {
+                self.info.seldepth = 0;
+                self.info.depth = d;
+                let mut pv = PVTable::new();
+                score =
+                    self.aspiration_window(false, board, &mut pv, score, d as i32, &mut best_move);
+
+                if self.stopped() {
                     break;
                 }
+
+                self.complete_iteration(&pv, score, d);
+                completed = (pv, score, d);
             }
 
-            return;
+            self.flush_nodes();
+            let (pv, score, depth) = completed;
+            return self.search_result(board, &pv, score, depth, info_timer);
         }
 
-        let info_timer = Instant::now();
         let mut best_move: Option<Move> = None;
-        let mut score = 0;
-        let mut pv = PVTable::new();
 
-        for d in 1..=depth {
+        // One PV and score per MultiPV line, capped at the number of searchable moves
+        let root_count = match self.info.root_moves.len() {
+            0 => movegen::pure_moves(board).len(),
+            n => n,
+        };
+        let lines = self.info.multipv.min(root_count).max(1);
+        let mut pvs: Vec<PVTable> = vec![PVTable::new(); lines];
+        let mut scores = vec![0; lines];
+        let mut completed_depth = 0;
+
+        'deepening: for d in 1..=depth {
             self.info.seldepth = 0;
-            score = self.aspiration_window(true, board, &mut pv, score, d as i32, &mut best_move);
+            self.info.depth = d;
+            self.info.fail_lows = 0;
+            self.info.excluded_moves.clear();
+
+            for line in 0..lines {
+                let mut pv = PVTable::new();
+                let mut line_best = pvs[line].best_move();
+                let score = self.aspiration_window(
+                    true,
+                    board,
+                    &mut pv,
+                    scores[line],
+                    d as i32,
+                    &mut line_best,
+                );
 
-            // Max time is up
-            if load_stop() && d > 1 {
-                break;
+                // Max time is up
+                if self.stopped() {
+                    break 'deepening;
+                }
+
+                scores[line] = score;
+                pvs[line] = pv;
+
+                if line == 0 {
+                    best_move = pvs[0].best_move();
+                }
+
+                // The following lines are searched without the moves already found
+                if let Some(mv) = pvs[line].best_move() {
+                    self.info.excluded_moves.push(mv);
+                }
             }
 
-            best_move = pv.best_move();
+            // A later line can overtake an earlier one, so order them by score
+            if lines > 1 {
+                let mut order: Vec<usize> = (0..lines).collect();
+                order.sort_by_key(|&i| std::cmp::Reverse(scores[i]));
+                pvs = order.iter().map(|&i| pvs[i].clone()).collect();
+                scores = order.iter().map(|&i| scores[i]).collect();
+                best_move = pvs[0].best_move();
+            }
 
-            let mut n = load_nodes();
-            if n == 0 {
-                n = self.info.nodes;
+            completed_depth = d;
+            self.complete_iteration(&pvs[0], scores[0], d);
+            let n = self.total_nodes();
+            let seldepth = self.shared.seldepth();
+
+            for line in 0..lines {
+                self.reporter.iteration(&IterationReport {
+                    depth: d,
+                    seldepth,
+                    multipv: line + 1,
+                    score: scores[line],
+                    bound: TTFlag::Exact,
+                    nodes: n,
+                    time: u128::from(self.elapsed(info_timer)),
+                    hashfull: self.tt.hashfull(),
+                    pv: pvs[line].moves().iter().flatten().copied().collect(),
+                });
             }
 
-            if pretty {
-                crate::uci::handler::pretty_print(
-                    d,
-                    self.info.seldepth,
-                    score,
-                    load_nodes(),
-                    info_timer.elapsed().as_millis(),
-                    pv.pv_string(),
-                );
-            } else {
-                println!(
-                    "info depth {} seldepth {} score {} nodes {} time {} nps {} pv{}",
-                    d,
-                    self.info.seldepth,
-                    format_score(score),
-                    n,
-                    info_timer.elapsed().as_millis(),
-                    n / info_timer.elapsed().as_secs().max(1),
-                    pv.pv_string()
-                );
+            self.info.excluded_moves.clear();
+
+            // Checkmate or stalemate, deeper iterations would only say the same
+            if root_count == 0 {
+                break;
             }
 
             // Nodes search type
             if let Some(nodes) = goal_nodes {
-                if self.info.nodes >= nodes {
+                if n >= nodes {
                     break;
                 }
             }
 
-            // Optimal time check
-            if let Some(mut opt) = opt_time {
-                // Time bound adjustments
-                #[rustfmt::skip]
-                let best_move_fraction =
-                    self.info.node_table
-                    [best_move.unwrap().from as usize]
-                    [best_move.unwrap().to as usize] as f64
-                    / self.info.nodes as f64;
-
-                let time_factor = (1.5 - best_move_fraction) * 1.35;
-                opt = (self.info.base_optimum.unwrap() as f64 * time_factor) as u64;
-
-                if info_timer.elapsed().as_millis() as u64 >= opt {
+            // Mate search type
+            // Stop as soon as a mate within the requested amount of moves is proven
+            if let Some(moves) = goal_mate {
+                if scores[0] >= MATE_IN && (MATE - scores[0] + 1) / 2 <= moves as i32 {
                     break;
                 }
             }
+
+            self.check_ponderhit();
+
+            // Optimal time check
+            if let (Some(soft_time), Some(best_move)) = (&mut soft_time, best_move) {
+                // The node table is indexed by the board's own castling moves
+                let mv = check_castling_move(board, best_move, self.info.chess960);
+                let best_move_fraction = self.info.node_table[mv.from as usize][mv.to as usize]
+                    as f64
+                    / self.info.nodes.max(1) as f64;
+
+                let opt = soft_time.optimum(
+                    best_move,
+                    scores[0],
+                    best_move_fraction,
+                    self.info.fail_lows,
+                );
+
+                // While pondering the clock hasn't started yet
+                if let Some(timer) = self.info.timer {
+                    if self.elapsed(timer) >= opt {
+                        break;
+                    }
+                }
+            }
         }
 
-        store_stop(true);
+        self.info.excluded_moves.clear();
+
+        // A finished ponder or infinite search holds its bestmove until ponderhit or stop
+        while ((self.info.pondering && self.shared.load_ponder()) || limits.infinite)
+            && !self.shared.load_stop()
+        {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        self.shared.store_stop(true);
+
+        let mut result = self.search_result(board, &pvs[0], scores[0], completed_depth, info_timer);
 
-        println!("bestmove {}", best_move.unwrap());
+        // With several threads the move is put to a vote, unless there are several
+        // lines to report or a fixed depth to keep to
+        if lines == 1 && limits.depth.is_none() {
+            self.vote_best_move(&mut result);
+        }
+
+        result
+    }
+
+    fn vote_best_move(&mut self, result: &mut SearchResult) {
+        let results = self.shared.thread_results();
+        let Some(best) = vote(&results) else {
+            return;
+        };
+
+        if best.thread_id == self.info.thread_id {
+            return;
+        }
+
+        result.best_move = best.pv.first().copied();
+        result.ponder = best.pv.get(1).copied();
+        result.pv.clone_from(&best.pv);
+        result.score = best.score;
+        result.depth = best.depth;
+
+        // The GUI gets to see the line the move comes from
+        self.reporter.iteration(&IterationReport {
+            depth: result.depth,
+            seldepth: result.seldepth,
+            multipv: 1,
+            score: result.score,
+            bound: TTFlag::Exact,
+            nodes: result.nodes,
+            time: result.time,
+            hashfull: self.tt.hashfull(),
+            pv: result.pv.clone(),
+        });
+    }
+
+    fn complete_iteration(&self, pv: &PVTable, score: i32, depth: usize) {
+        self.shared.complete_iteration(ThreadResult {
+            thread_id: self.info.thread_id,
+            pv: pv.moves().iter().flatten().copied().collect(),
+            score,
+            depth,
+            seldepth: self.info.seldepth,
+        });
+    }
+
+    // Whether to give up on the iteration. The first one is always finished, so even
+    // a search which is out of time before it starts plays a move it has looked at.
+    fn stopped(&self) -> bool {
+        self.info.depth > 1 && self.shared.load_stop()
+    }
+
+    // Stops every thread once the max time is up. Any thread may find out first, on a
+    // node clock the time is that of all threads together.
+    fn check_time(&self) {
+        if let (Some(timer), Some(max)) = (self.info.timer, self.info.max_time) {
+            if self.info.nodes.is_multiple_of(self.info.clock.interval())
+                && self.elapsed(timer) >= max
+            {
+                self.shared.store_stop(true);
+            }
+        }
+    }
+
+    // Stops every thread once all of them together have searched the nodes asked for
+    fn check_node_limit(&self) {
+        if let Some(nodes) = self.info.limits.nodes {
+            if self.shared_nodes() >= nodes {
+                self.shared.store_stop(true);
+            }
+        }
+    }
+
+    // Adds the nodes we haven't told the other threads about yet
+    fn flush_nodes(&mut self) {
+        self.shared
+            .add_nodes(self.info.nodes - self.info.prev_nodes);
+        self.info.prev_nodes = self.info.nodes;
+    }
+
+    // Nodes of every thread, ours up to date
+    fn total_nodes(&mut self) -> u64 {
+        self.flush_nodes();
+        self.shared.load_nodes()
+    }
+
+    // Nodes of every thread, without flushing ours
+    fn shared_nodes(&self) -> u64 {
+        self.shared.load_nodes() + (self.info.nodes - self.info.prev_nodes)
+    }
+
+    // Counting the nodes of every thread, so more threads don't mean more time
+    fn now(&self) -> u64 {
+        self.info.clock.now(self.shared_nodes())
+    }
+
+    // The reading at the start of the search, before any thread searched a node. Taking
+    // it later would give threads that start late more time on a node clock.
+    fn start(&self) -> u64 {
+        self.info.clock.now(0)
+    }
+
+    // Milliseconds since the clock read `start`
+    fn elapsed(&self, start: u64) -> u64 {
+        self.now().saturating_sub(start)
+    }
+
+    fn search_result(
+        &mut self,
+        board: &Board,
+        pv: &PVTable,
+        score: i32,
+        depth: usize,
+        timer: u64,
+    ) -> SearchResult {
+        let pv: Vec<Move> = pv.moves().iter().flatten().copied().collect();
+
+        let mut root_nodes: Vec<(Move, u64)> = movegen::pure_moves(board)
+            .into_iter()
+            .filter(|mv| self.info.root_moves.is_empty() || self.info.root_moves.contains(mv))
+            .map(|mv| {
+                (
+                    reverse_castling_move(board, mv, self.info.chess960),
+                    self.info.node_table[mv.from as usize][mv.to as usize],
+                )
+            })
+            .collect();
+        root_nodes.sort_by_key(|&(_, nodes)| std::cmp::Reverse(nodes));
+
+        SearchResult {
+            best_move: pv.first().copied(),
+            ponder: pv.get(1).copied(),
+            pv,
+            score,
+            depth,
+            // Only completed iterations, an aborted one may have gone deeper
+            seldepth: self.shared.seldepth(),
+            nodes: self.total_nodes(),
+            time: u128::from(self.elapsed(timer)),
+            root_nodes,
+        }
+    }
+
+    // The ponder search has turned into a regular one, so the clock starts now
+    fn check_ponderhit(&mut self) {
+        if self.info.pondering && !self.shared.load_ponder() {
+            self.info.pondering = false;
+
+            if self.info.max_time.is_some() {
+                self.info.timer = Some(self.now());
+            }
+        }
     }
 
     fn aspiration_window(
@@ -751,12 +1216,17 @@ impl<'a> Search<'a> {
         loop {
             score = self.pvsearch::<true>(main_thread, board, pv, alpha, beta, depth, 0);
 
-            if load_stop() {
+            if self.stopped() {
                 return 0;
             }
 
             // Search failed low
             if score <= alpha {
+                if main_thread {
+                    self.info.fail_lows += 1;
+                    self.report_bound(pv, score, init_depth, TTFlag::UpperBound, *best_move);
+                }
+
                 beta = (alpha + beta) / 2;
                 alpha = (-INFINITY).max(score - delta);
                 depth = init_depth;
@@ -768,6 +1238,10 @@ impl<'a> Search<'a> {
                 depth -= i32::from(score.abs() < MATE_IN);
 
                 *best_move = pv.best_move();
+
+                if main_thread {
+                    self.report_bound(pv, score, init_depth, TTFlag::LowerBound, *best_move);
+                }
             }
             // Search succeeded
             else {
@@ -779,6 +1253,40 @@ impl<'a> Search<'a> {
         }
     }
 
+    // A root window which failed, once the search has gone on long enough for the GUI
+    // to be left waiting. A fail low has no PV of its own, so it shows the move it refutes.
+    fn report_bound(
+        &mut self,
+        pv: &PVTable,
+        score: i32,
+        depth: i32,
+        bound: TTFlag,
+        best_move: Option<Move>,
+    ) {
+        if self.elapsed(self.info.search_start) < INFO_DELAY {
+            return;
+        }
+
+        let mut moves: Vec<Move> = pv.moves().iter().flatten().copied().collect();
+        if moves.is_empty() {
+            moves.extend(best_move);
+        }
+
+        let report = IterationReport {
+            depth: depth as usize,
+            seldepth: self.shared.seldepth().max(self.info.seldepth),
+            // The lines already searched this iteration are excluded from this one
+            multipv: self.info.excluded_moves.len() + 1,
+            score,
+            bound,
+            nodes: self.total_nodes(),
+            time: u128::from(self.elapsed(self.info.search_start)),
+            hashfull: self.tt.hashfull(),
+            pv: moves,
+        };
+        self.reporter.iteration(&report);
+    }
+
     fn repetition(&self, board: &Board, hash: u64) -> bool {
         self.info
             .game_history
@@ -800,81 +1308,67 @@ impl<'a> Search<'a> {
     }
 
     pub fn go_reset(&mut self) {
-        STOP.store(false, Ordering::Relaxed);
-        NODES.store(0, Ordering::Relaxed);
-        self.info.search_type = SearchType::Depth(0);
-        self.info.timer = None;
-        self.info.max_time = None;
-        self.info.base_optimum = None;
-        self.info.nodes = 0;
-        self.info.node_table = [[0; 64]; 64];
-        self.info.seldepth = 0;
+        self.shared.store_stop(false);
+        self.shared.clear_search();
+        self.info.new_search();
         self.info.killers = [[None; 2]; MAX_PLY];
         self.info.history.age_table();
     }
 
     pub fn game_reset(&mut self) {
-        STOP.store(false, Ordering::Relaxed);
-        NODES.store(0, Ordering::Relaxed);
+        self.shared.store_stop(false);
+        self.shared.clear_search();
         self.info = SearchInfo::new();
         self.info.game_history = vec![Board::default().hash()];
     }
-
-    pub fn data_search(&mut self, board: &Board, st: SearchType) -> (i32, Move) {
-        let depth: usize;
-        let mut goal_nodes: Option<u64> = None;
-        match st {
-            SearchType::Depth(d) => depth = (d).min(MAX_PLY),
-            SearchType::Nodes(n) => {
-                depth = MAX_PLY;
-                goal_nodes = Some(n);
-            }
-            _ => unreachable!(),
-        };
-
-        let mut best_move: Option<Move> = None;
-
-        let mut score = 0;
-        let mut pv = PVTable::new();
-
-        for d in 1..=depth {
-            self.info.seldepth = 0;
-            score = self.aspiration_window(true, board, &mut pv, score, d as i32, &mut best_move);
-
-            if load_stop() && d > 1 {
-                break;
-            }
-
-            best_move = pv.best_move();
-
-            if let Some(nodes) = goal_nodes {
-                if self.info.nodes >= nodes {
-                    break;
-                }
-            }
-        }
-
-        (score, best_move.unwrap())
-    }
 }
 
 pub fn format_score(score: i32) -> String {
     debug_assert!(score < NONE);
-    let print_score: String;
-    if score >= MATE_IN {
-        print_score = format!("mate {}", (((MATE - score) / 2) + ((MATE - score) & 1)));
-    } else if score <= -MATE_IN {
-        print_score = format!("mate {}", -(((MATE + score) / 2) + ((MATE + score) & 1)));
-    } else {
-        print_score = format!("cp {score}");
-    }
-
-    print_score
+    Score::from(score).to_string()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::uci::{
+        handler::Clock,
+        reporter::{CollectingReporter, SilentReporter},
+        timeman::NodeClock,
+    };
+
+    // Everything a search borrows, set up for one position
+    struct Setup {
+        tt: TT,
+        shared: SharedContext,
+        nnue: Box<NNUEState>,
+        history: History,
+        game_history: Vec<u64>,
+    }
+
+    fn setup(board: &Board) -> Setup {
+        Setup {
+            tt: TT::new(16),
+            shared: SharedContext::new(),
+            nnue: NNUEState::from_board(board),
+            history: History::new(),
+            game_history: vec![board.hash()],
+        }
+    }
+
+    impl Setup {
+        fn search<'a>(&'a self, reporter: impl Reporter + Send + 'a) -> Search<'a> {
+            let mut search = Search::new(
+                &self.tt,
+                &self.shared,
+                &self.nnue,
+                &self.history,
+                &self.game_history,
+            );
+            search.reporter = Box::new(reporter);
+            search
+        }
+    }
 
     #[test]
     fn repetitions() {
@@ -884,20 +1378,536 @@ mod tests {
             "5rk1/1rP3pp/p4n2/3Pp3/1P2Pq2/2Q4P/P5P1/R3R1K1 b - - 0 32",
         ];
 
-        let tt = TT::new(16);
-        let nnue = NNUEState::from_board(&Board::default());
-        let history = History::new();
-        let mut search = Search::new(&tt, &nnue, &history, &vec![]);
+        let setup = Setup {
+            game_history: vec![],
+            ..setup(&Board::default())
+        };
+        let mut search = setup.search(SilentReporter);
 
         for fen in FENS.iter() {
             let board = Board::from_fen(fen, false).unwrap();
             search.nnue.refresh(&board);
 
-            let (score, _) = search.data_search(&board, SearchType::Depth(20));
+            let score = search
+                .iterative_deepening::<true>(&board, SearchLimits::depth(20))
+                .score;
 
             assert!((-10..=10).contains(&score), "{score} {fen}");
 
             search.game_reset();
         }
     }
+
+    #[test]
+    fn no_legal_moves() {
+        // Checkmated, then stalemated with its slightly randomised draw score
+        for (fen, scores) in [
+            ("7k/6Q1/6K1/8/8/8/8/8 b - - 0 1", -MATE..=-MATE),
+            ("7k/5Q2/6K1/8/8/8/8/8 b - - 0 1", 1..=8),
+        ] {
+            let board: Board = fen.parse().unwrap();
+            let setup = setup(&board);
+            let mut collector = CollectingReporter::default();
+
+            let mut search = setup.search(&mut collector);
+            let result = search.iterative_deepening::<true>(&board, SearchLimits::depth(5));
+            drop(search);
+
+            // A single report, not one per depth
+            assert_eq!(collector.iterations.len(), 1);
+            assert!(collector.iterations[0].pv.is_empty());
+            assert_eq!(result.best_move, None);
+            assert!(scores.contains(&result.score));
+            assert_eq!(result.depth, 1);
+        }
+    }
+
+    #[test]
+    fn collected_reports() {
+        let board = Board::default();
+        let setup = setup(&board);
+        let mut collector = CollectingReporter::default();
+
+        let mut search = setup.search(&mut collector);
+        search.info.multipv = 3;
+        let result = search.iterative_deepening::<true>(&board, SearchLimits::depth(5));
+        search.game_reset();
+        drop(search);
+
+        assert_eq!(collector.iterations.len(), 5 * 3);
+        for (i, lines) in collector.iterations.chunks(3).enumerate() {
+            // Distinct root moves, best lines first
+            assert!(lines.iter().all(|r| r.depth == i + 1));
+            assert_eq!(
+                lines.iter().map(|r| r.multipv).collect::<Vec<_>>(),
+                vec![1, 2, 3]
+            );
+            assert!(lines.windows(2).all(|w| w[0].score >= w[1].score));
+            assert_ne!(lines[0].pv[0], lines[1].pv[0]);
+            assert_ne!(lines[1].pv[0], lines[2].pv[0]);
+            assert_ne!(lines[0].pv[0], lines[2].pv[0]);
+        }
+
+        // The result matches the best line of the last iteration
+        let last = &collector.iterations[collector.iterations.len() - 3];
+        assert_eq!(result.best_move, Some(last.pv[0]));
+        assert_eq!(result.ponder, last.pv.get(1).copied());
+        assert_eq!(result.pv, last.pv);
+        assert_eq!(result.score, last.score);
+        assert_eq!(result.depth, 5);
+        assert_eq!(result.seldepth, last.seldepth);
+
+        // Every root move got searched, and the counts are sorted
+        assert_eq!(result.root_nodes.len(), 20);
+        assert!(result.root_nodes.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(result.root_nodes.iter().map(|(_, n)| n).sum::<u64>() <= result.nodes);
+    }
+
+    #[test]
+    fn scores() {
+        assert_eq!(Score::from(35), Score::Cp(35));
+        assert_eq!(Score::from(MATE - 1), Score::Mate(1));
+        assert_eq!(Score::from(MATE - 3), Score::Mate(2));
+        assert_eq!(Score::from(-MATE + 2), Score::Mate(-1));
+        assert_eq!(Score::from(-MATE + 4), Score::Mate(-2));
+        assert_eq!(format_score(-120), "cp -120");
+        assert_eq!(format_score(MATE - 5), "mate 3");
+    }
+
+    #[test]
+    fn first_limit_wins() {
+        let board = Board::default();
+        let setup = setup(&board);
+        let mut search = setup.search(SilentReporter);
+
+        // The depth limit comes long before the node limit
+        let limits = SearchLimits {
+            depth: Some(3),
+            nodes: Some(u64::MAX),
+            movetime: Some(60_000),
+            ..SearchLimits::default()
+        };
+        assert_eq!(search.iterative_deepening::<true>(&board, limits).depth, 3);
+        search.go_reset();
+
+        // ... and the movetime long before the depth limit
+        let limits = SearchLimits {
+            depth: Some(MAX_PLY),
+            movetime: Some(50),
+            ..SearchLimits::default()
+        };
+        let result = search.iterative_deepening::<true>(&board, limits);
+        assert!(result.depth < MAX_PLY);
+        assert!(result.time < 1000);
+    }
+
+    #[test]
+    fn mate_search() {
+        // The last two hide their mates from a search which prunes and reduces
+        const MATES: [(&str, usize); 3] = [
+            ("r5k1/5ppp/8/8/8/8/3R1PPP/3R2K1 w - - 0 1", 2),
+            ("1r6/4b2k/1q1pNrpp/p2Pp3/4P3/1P1R3Q/5PPP/5RK1 w - - 0 1", 2),
+            (
+                "r1b1kb1r/pppp1ppp/5q2/4n3/3KP3/2N3PN/PPP4P/R1BQ1B1R b kq - 0 1",
+                3,
+            ),
+        ];
+
+        for (fen, moves) in MATES {
+            let board = Board::from_fen(fen, false).unwrap();
+            let setup = setup(&board);
+            let mut collector = CollectingReporter::default();
+
+            let mut search = setup.search(&mut collector);
+            search.iterative_deepening::<true>(
+                &board,
+                SearchLimits {
+                    mate: Some(moves),
+                    ..SearchLimits::default()
+                },
+            );
+            search.game_reset();
+            drop(search);
+
+            // Searching every move at full depth, the mate can't take longer than 2N - 1 plies
+            let last = collector.iterations.last().unwrap();
+            assert_eq!(format_score(last.score), format!("mate {moves}"), "{fen}");
+            assert!(last.depth < 2 * moves, "{fen}");
+        }
+    }
+
+    #[test]
+    fn independent_contexts() {
+        let board = Board::default();
+
+        let solo_nodes = {
+            let setup = setup(&board);
+            let mut search = setup.search(SilentReporter);
+            search.iterative_deepening::<true>(&board, SearchLimits::depth(8));
+            search.info.nodes
+        };
+
+        // A short search stopping itself must not cut the other one short
+        let (a, b) = (setup(&board), setup(&board));
+        let nodes = std::thread::scope(|s| {
+            s.spawn(|| {
+                let mut search = a.search(SilentReporter);
+                search.iterative_deepening::<true>(&board, SearchLimits::depth(2));
+            });
+            s.spawn(|| {
+                let mut search = b.search(SilentReporter);
+                search.iterative_deepening::<true>(&board, SearchLimits::depth(8));
+                search.info.nodes
+            })
+            .join()
+            .unwrap()
+        });
+
+        assert!(a.shared.load_stop());
+        assert_eq!(nodes, solo_nodes);
+    }
+
+    // White wins a knight with gxh5, though taking the rook comes first in move order
+    const HANGING: &str = "6k1/5pp1/2p5/3r3n/6P1/1Q6/8/6K1 w - - 0 1";
+
+    // Searches on a clock where 20 nodes take a millisecond, with the soft time adjustments off
+    fn clocked_search(
+        board: &Board,
+        clock: Clock,
+    ) -> (Vec<IterationReport>, SearchResult, (u64, u64)) {
+        let setup = setup(board);
+        let mut collector = CollectingReporter::default();
+
+        let mut search = setup.search(&mut collector);
+        search.info.clock = Arc::new(NodeClock::new(20));
+        search.info.time_options = TimeOptions {
+            node_fraction: false,
+            best_move_stability: false,
+            score_trend: false,
+            fail_lows: false,
+            ..TimeOptions::default()
+        };
+
+        let limits = SearchLimits {
+            clock: Some(clock),
+            ..SearchLimits::default()
+        };
+        let bounds = limits
+            .time_bounds(false, &search.info.time_options)
+            .unwrap();
+        let result = search.iterative_deepening::<true>(board, limits);
+        drop(search);
+
+        (collector.iterations, result, bounds)
+    }
+
+    // Every iteration but the last one ended before the optimum, and the search stopped
+    // either right after an iteration past the optimum or as soon as the max time was up
+    fn assert_stop(reports: &[IterationReport], result: &SearchResult, (opt, max): (u64, u64)) {
+        let (last, earlier) = reports.split_last().unwrap();
+        assert!(earlier.iter().all(|r| r.time < u128::from(opt)));
+        assert_eq!(result.depth, last.depth);
+
+        if last.time >= u128::from(opt) {
+            assert_eq!(result.time, last.time);
+        } else {
+            assert!(result.time >= u128::from(max));
+            assert!(result.time <= u128::from(max) + 1);
+        }
+    }
+
+    #[test]
+    fn sudden_death() {
+        let (reports, result, bounds) = clocked_search(
+            &Board::default(),
+            Clock {
+                time: 10_000 + TIME_OVERHEAD,
+                inc: 0,
+                moves_to_go: None,
+            },
+        );
+        assert_eq!(bounds, (300, 1000));
+        assert_stop(&reports, &result, bounds);
+    }
+
+    #[test]
+    fn increment() {
+        let (reports, result, bounds) = clocked_search(
+            &Board::default(),
+            Clock {
+                time: 2000 + TIME_OVERHEAD,
+                inc: 1000,
+                moves_to_go: None,
+            },
+        );
+        assert_eq!(bounds, (510, 1700));
+        assert_stop(&reports, &result, bounds);
+    }
+
+    #[test]
+    fn moves_to_go() {
+        let (reports, result, bounds) = clocked_search(
+            &Board::default(),
+            Clock {
+                time: 10_000 + TIME_OVERHEAD,
+                inc: 0,
+                moves_to_go: Some(35),
+            },
+        );
+        assert_eq!(bounds, (200, 1000));
+        assert_stop(&reports, &result, bounds);
+    }
+
+    #[test]
+    fn clock_below_overhead() {
+        // No time at all still finishes the first iteration, and plays what it found
+        let board = HANGING.parse().unwrap();
+        let (reports, result, bounds) = clocked_search(
+            &board,
+            Clock {
+                time: TIME_OVERHEAD - 2,
+                inc: 0,
+                moves_to_go: None,
+            },
+        );
+        assert_eq!(bounds, (0, 0));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(result.depth, 1);
+        assert_eq!(result.best_move, Some("g4h5".parse().unwrap()));
+    }
+
+    #[test]
+    fn root_progress() {
+        let board = Board::default();
+        let small = Setup {
+            tt: TT::new(1),
+            ..setup(&board)
+        };
+        let mut collector = CollectingReporter::default();
+
+        let mut search = small.search(&mut collector);
+        search.iterative_deepening::<true>(&board, SearchLimits::depth(4));
+        drop(search);
+
+        // A quick search only reports its iterations
+        assert!(collector.current_moves.is_empty());
+        assert!(collector.iterations.last().unwrap().hashfull > 0);
+
+        // With every node taking a millisecond the root moves show up after a few thousand.
+        // Also with the side to move in check, where the search extends the root.
+        let check: Board = "rnbqkbnr/ppp1pppp/8/1B1p4/4P3/8/PPPP1PPP/RNBQK1NR b KQkq - 1 2"
+            .parse()
+            .unwrap();
+        for board in [board, check] {
+            let setup = setup(&board);
+            let mut collector = CollectingReporter::default();
+            let mut search = setup.search(&mut collector);
+            search.info.clock = Arc::new(NodeClock::new(1));
+            search.iterative_deepening::<true>(&board, SearchLimits::nodes(30_000));
+            drop(search);
+
+            let moves = movegen::pure_moves(&board);
+            let current = &collector.current_moves;
+            assert!(!current.is_empty());
+            assert!(current.iter().all(|(_, mv, _)| moves.contains(mv)));
+            for pair in current.windows(2) {
+                let ((depth, _, number), (next_depth, _, next_number)) = (pair[0], pair[1]);
+                if next_depth == depth {
+                    assert_eq!(next_number, number + 1);
+                } else {
+                    assert_eq!(next_number, 1);
+                }
+            }
+
+            // The depth is that of the iteration, which comes right after a finished one
+            let reports = &collector.iterations;
+            assert!(current
+                .iter()
+                .all(|&(depth, _, _)| depth == 1 || reports.iter().any(|r| r.depth == depth - 1)));
+
+            // Nothing for iterations which were over before the delay
+            for report in reports {
+                if report.time < u128::from(INFO_DELAY) {
+                    assert!(current.iter().all(|&(depth, _, _)| depth != report.depth));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn aspiration_bounds() {
+        let board: Board = "r3k2r/p1ppqpb1/bn2pnp1/3PN3/1p2P3/2N2Q1p/PPPBBPPP/R3K2R w KQkq - 0 1"
+            .parse()
+            .unwrap();
+        let setup = setup(&board);
+        let mut collector = CollectingReporter::default();
+
+        let mut search = setup.search(&mut collector);
+        search.info.clock = Arc::new(NodeClock::new(1));
+        search.iterative_deepening::<true>(&board, SearchLimits::depth(9));
+        drop(search);
+
+        // The GUI hears about the failed window before the iteration is over
+        let reports = &collector.iterations;
+        let failed = reports
+            .iter()
+            .position(|r| r.bound == TTFlag::UpperBound)
+            .unwrap();
+        let (report, rest) = (&reports[failed], &reports[failed + 1..]);
+        assert!(report.time >= u128::from(INFO_DELAY));
+        // A fail low has no PV, just the move which didn't hold up
+        assert_eq!(report.pv.len(), 1);
+        assert!(rest
+            .iter()
+            .any(|r| r.depth == report.depth && r.bound == TTFlag::Exact));
+
+        // Iterations over before the delay never show their failed windows
+        assert!(reports
+            .iter()
+            .all(|r| r.bound == TTFlag::Exact || r.time >= u128::from(INFO_DELAY)));
+    }
+
+    fn thread_result(thread_id: usize, mv: &str, score: i32, depth: usize) -> ThreadResult {
+        ThreadResult {
+            thread_id,
+            pv: vec![mv.parse().unwrap()],
+            score,
+            depth,
+            seldepth: depth,
+        }
+    }
+
+    #[test]
+    fn votes() {
+        assert_eq!(vote(&[]), None);
+
+        // Two threads agreeing at a greater depth outvote the main thread
+        let results = [
+            thread_result(0, "e2e4", 20, 10),
+            thread_result(1, "d2d4", 30, 12),
+            thread_result(2, "d2d4", 25, 11),
+        ];
+        assert_eq!(vote(&results), Some(&results[1]));
+
+        // Ties go to the main thread
+        let results = [
+            thread_result(0, "e2e4", 20, 10),
+            thread_result(1, "d2d4", 20, 10),
+        ];
+        assert_eq!(vote(&results), Some(&results[0]));
+
+        // A thread without a move has no say, and a proven mate needs no votes
+        let results = [
+            thread_result(0, "e2e4", 20, 10),
+            thread_result(1, "e2e4", 20, 10),
+            thread_result(2, "g1f3", MATE - 9, 8),
+            ThreadResult {
+                pv: vec![],
+                ..thread_result(3, "e2e4", MATE - 1, 20)
+            },
+        ];
+        assert_eq!(vote(&results), Some(&results[2]));
+    }
+
+    #[test]
+    fn node_limit() {
+        let board = Board::default();
+        let startpos = setup(&board);
+
+        // The search stops in the middle of an iteration, not after it
+        for limit in [5000, 12_345] {
+            let mut search = startpos.search(SilentReporter);
+            let result = search.iterative_deepening::<true>(&board, SearchLimits::nodes(limit));
+            // Give or take the few nodes on their way back up
+            let nodes = result.nodes;
+            assert!((limit..limit + 32).contains(&nodes), "{limit} {nodes}");
+            assert!(result.best_move.is_some());
+
+            search.game_reset();
+        }
+
+        // Though the first one is always finished, for a move to play
+        let board = HANGING.parse().unwrap();
+        let setup = setup(&board);
+        let mut search = setup.search(SilentReporter);
+        let result = search.iterative_deepening::<true>(&board, SearchLimits::nodes(1));
+        assert_eq!(result.depth, 1);
+        assert_eq!(result.best_move, Some("g4h5".parse().unwrap()));
+    }
+
+    #[test]
+    fn lazy_smp() {
+        let board = Board::default();
+        let setup = setup(&board);
+        let limits = SearchLimits::nodes(20_000);
+
+        let mut searchers: Vec<Search> = (0..4)
+            .map(|thread_id| {
+                let mut search = setup.search(SilentReporter);
+                search.info.thread_id = thread_id;
+                search
+            })
+            .collect();
+
+        let (main, helpers) = searchers.split_first_mut().unwrap();
+        let result = std::thread::scope(|s| {
+            for helper in helpers.iter_mut() {
+                s.spawn(|| helper.iterative_deepening::<false>(&board, limits));
+            }
+
+            main.iterative_deepening::<true>(&board, limits)
+        });
+
+        // Every node of every thread is counted, and together they keep to the limit.
+        // Each helper may be up to a flush behind when the main thread stops.
+        let nodes: u64 = searchers.iter().map(|s| s.info.nodes).sum();
+        assert_eq!(setup.shared.load_nodes(), nodes);
+        assert!(result.nodes <= nodes);
+        assert!(nodes < 20_000 + 3 * 2048 + 100, "{nodes}");
+
+        // The helpers took part, and the move comes from the winner of the vote
+        let results = setup.shared.thread_results();
+        assert!(results.len() > 1);
+        let best = vote(&results).unwrap();
+        assert_eq!(result.best_move, best.pv.first().copied());
+        assert_eq!(result.pv, best.pv);
+        assert_eq!(result.depth, best.depth);
+        assert_eq!(
+            result.seldepth,
+            results.iter().map(|r| r.seldepth).max().unwrap()
+        );
+    }
+
+    #[test]
+    fn shared_node_clock() {
+        let board = Board::default();
+        let setup = setup(&board);
+        let limits = SearchLimits {
+            movetime: Some(500),
+            ..SearchLimits::default()
+        };
+
+        let mut searchers: Vec<Search> = (0..4)
+            .map(|thread_id| {
+                let mut search = setup.search(SilentReporter);
+                search.info.thread_id = thread_id;
+                search.info.clock = Arc::new(NodeClock::new(20));
+                search
+            })
+            .collect();
+
+        let (main, helpers) = searchers.split_first_mut().unwrap();
+        std::thread::scope(|s| {
+            for helper in helpers.iter_mut() {
+                s.spawn(|| helper.iterative_deepening::<false>(&board, limits));
+            }
+
+            main.iterative_deepening::<true>(&board, limits)
+        });
+
+        // 500ms at 20 nodes per ms are 10000 nodes for all threads together, not each.
+        // Each thread may be up to a flush behind on the others when it reads the clock.
+        let nodes = setup.shared.load_nodes();
+        assert!(nodes < 10_000 + 3 * 2048 + 100, "{nodes}");
+    }
 }
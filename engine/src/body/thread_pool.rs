@@ -0,0 +1,375 @@
+use super::{
+    nnue::inference::NNUEState,
+    search::{Search, SearchInfo, SearchResult, SharedContext},
+    tt::TT,
+};
+
+use crate::uci::{
+    handler::SearchLimits,
+    reporter::{Reporter, SilentReporter},
+    timeman::TimeOptions,
+};
+
+use cozy_chess::{Board, Move};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc,
+};
+use std::thread::JoinHandle;
+
+// Everything the threads need to know for one `go`
+#[derive(Clone)]
+pub struct SearchJob {
+    pub tt: Arc<TT>,
+    pub shared: Arc<SharedContext>,
+    pub board: Board,
+    pub game_history: Vec<u64>,
+    pub limits: SearchLimits,
+    pub search_moves: Vec<Move>,
+    pub multipv: usize,
+    pub ponder: bool,
+    pub time_options: TimeOptions,
+    pub chess960: bool,
+}
+
+enum Message {
+    // Only the main thread gets a reporter
+    Search(Box<SearchJob>, Option<Box<dyn Reporter + Send>>),
+    // A new game, so the history and killers of the old one are no use anymore
+    Clear,
+}
+
+// What a thread sends back once it's done, an error if its search panicked
+type Done = Result<Option<SearchResult>, ()>;
+
+struct Worker {
+    jobs: Sender<Message>,
+    handle: JoinHandle<()>,
+}
+
+// Search threads which live from one `go` to the next, each keeping its own history
+// and killers. The first one is the main thread, it reports and decides when to stop.
+pub struct ThreadPool {
+    workers: Vec<Worker>,
+    done_sender: Sender<Done>,
+    done: Receiver<Done>,
+    // Threads which haven't finished the current search yet
+    busy: usize,
+    result: Option<SearchResult>,
+    // Searches which panicked on any of the threads
+    failures: usize,
+}
+
+impl ThreadPool {
+    pub fn new(threads: usize) -> Self {
+        let (done_sender, done) = mpsc::channel();
+        let mut pool = ThreadPool {
+            workers: vec![],
+            done_sender,
+            done,
+            busy: 0,
+            result: None,
+            failures: 0,
+        };
+
+        pool.resize(threads);
+        pool
+    }
+
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+
+    // Only while no search is running, new threads start out with an empty history
+    pub fn resize(&mut self, threads: usize) {
+        debug_assert!(self.busy == 0);
+        let threads = threads.max(1);
+
+        while self.workers.len() > threads {
+            let worker = self.workers.pop().unwrap();
+            drop(worker.jobs);
+            worker.handle.join().unwrap();
+        }
+
+        while self.workers.len() < threads {
+            let (jobs, receiver) = mpsc::channel();
+            let done = self.done_sender.clone();
+            let thread_id = self.workers.len();
+            let handle = std::thread::spawn(move || work(thread_id, receiver, done));
+
+            self.workers.push(Worker { jobs, handle });
+        }
+    }
+
+    // Starts a search on every thread and returns right away
+    pub fn start(&mut self, job: SearchJob, reporter: Box<dyn Reporter + Send>) {
+        debug_assert!(self.busy == 0);
+        let mut reporter = Some(reporter);
+
+        for worker in &self.workers {
+            let message = Message::Search(Box::new(job.clone()), reporter.take());
+            worker.jobs.send(message).unwrap();
+        }
+
+        self.busy = self.workers.len();
+    }
+
+    pub fn is_searching(&mut self) -> bool {
+        while self.busy > 0 {
+            let Ok(result) = self.done.try_recv() else {
+                break;
+            };
+            self.finish(result);
+        }
+
+        self.busy > 0
+    }
+
+    // Blocks until every thread is done. The result of the main thread,
+    // or None if nothing was searched since the last wait.
+    pub fn wait(&mut self) -> Option<SearchResult> {
+        while self.busy > 0 {
+            let result = self.done.recv().unwrap();
+            self.finish(result);
+        }
+
+        self.result.take()
+    }
+
+    pub fn clear(&mut self) {
+        debug_assert!(self.busy == 0);
+
+        for worker in &self.workers {
+            worker.jobs.send(Message::Clear).unwrap();
+        }
+    }
+
+    fn finish(&mut self, done: Done) {
+        self.busy -= 1;
+        match done {
+            Ok(Some(result)) => self.result = Some(result),
+            Ok(None) => (),
+            Err(()) => self.failures += 1,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        self.wait();
+
+        // A closed channel ends the thread
+        for worker in self.workers.drain(..) {
+            drop(worker.jobs);
+            worker.handle.join().unwrap();
+        }
+    }
+}
+
+// The loop of a single thread, the search state stays here between jobs
+fn work(thread_id: usize, jobs: Receiver<Message>, done: Sender<Done>) {
+    let mut nnue = NNUEState::from_board(&Board::default());
+    let mut info = SearchInfo::new();
+
+    while let Ok(message) = jobs.recv() {
+        let (job, reporter) = match message {
+            Message::Search(job, reporter) => (job, reporter),
+            Message::Clear => {
+                info = SearchInfo::new();
+                continue;
+            }
+        };
+
+        // A panic costs the thread its search and its history, but never leaves the pool waiting
+        let searched = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut search = Search {
+                nnue,
+                tt: &job.tt,
+                shared: &job.shared,
+                info,
+                reporter: reporter.unwrap_or_else(|| Box::new(SilentReporter)),
+            };
+
+            search.nnue.refresh(&job.board);
+            search.info.new_search();
+            search.info.thread_id = thread_id;
+            search.info.game_history = job.game_history.clone();
+            search.info.multipv = job.multipv;
+            search.info.pondering = job.shared.load_ponder();
+            search.info.ponder = job.ponder;
+            search.info.time_options = job.time_options;
+            search.info.clock = job.time_options.clock();
+            search.info.root_moves = job.search_moves.clone();
+            search.info.chess960 = job.chess960;
+
+            let result = if thread_id == 0 {
+                let result = search.iterative_deepening::<true>(&job.board, job.limits);
+                search.reporter.best_move(result.best_move, result.ponder);
+                Some(result)
+            } else {
+                search.iterative_deepening::<false>(&job.board, job.limits);
+                None
+            };
+
+            search.info.history.age_table();
+            (result, search.nnue, search.info)
+        }));
+
+        let result = match searched {
+            Ok((result, searched_nnue, searched_info)) => {
+                (nnue, info) = (searched_nnue, searched_info);
+                Ok(result)
+            }
+            Err(payload) => {
+                println!(
+                    "info string search thread {thread_id} failed: {}",
+                    panic_message(payload.as_ref())
+                );
+                // The other threads might only stop when told to
+                job.shared.store_stop(true);
+                (nnue, info) = (NNUEState::from_board(&Board::default()), SearchInfo::new());
+                Err(())
+            }
+        };
+
+        // The TT and shared context have to be let go before anyone hears we're done
+        drop(job);
+        done.send(result).unwrap();
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::uci::reporter::IterationReport;
+
+    // Fails the search as soon as it has something to say
+    struct PanickingReporter;
+
+    impl Reporter for PanickingReporter {
+        fn iteration(&mut self, _: &IterationReport) {
+            panic!("reporter failed");
+        }
+
+        fn current_move(&mut self, _: usize, _: Move, _: usize) {}
+
+        fn best_move(&mut self, _: Option<Move>, _: Option<Move>) {}
+    }
+
+    fn job(tt: &Arc<TT>, shared: &Arc<SharedContext>, limits: SearchLimits) -> SearchJob {
+        let board = Board::default();
+
+        SearchJob {
+            tt: Arc::clone(tt),
+            shared: Arc::clone(shared),
+            game_history: vec![board.hash()],
+            board,
+            limits,
+            search_moves: vec![],
+            multipv: 1,
+            ponder: false,
+            time_options: TimeOptions::default(),
+            chess960: false,
+        }
+    }
+
+    #[test]
+    fn persistent_threads() {
+        let mut tt = Arc::new(TT::new(16));
+        let shared = Arc::new(SharedContext::new());
+        let mut pool = ThreadPool::new(3);
+        assert_eq!(pool.wait(), None);
+
+        for depth in [4, 6] {
+            pool.start(
+                job(&tt, &shared, SearchLimits::depth(depth)),
+                Box::new(SilentReporter),
+            );
+            let result = pool.wait().unwrap();
+            assert_eq!(result.depth, depth);
+            assert!(result.best_move.is_some());
+            assert!(!pool.is_searching());
+
+            // Nothing holds on to the TT between searches
+            assert!(Arc::get_mut(&mut tt).is_some());
+            shared.store_stop(false);
+            shared.clear_search();
+        }
+
+        pool.resize(1);
+        assert_eq!(pool.threads(), 1);
+        pool.clear();
+        pool.resize(4);
+        assert_eq!(pool.threads(), 4);
+
+        pool.start(
+            job(&tt, &shared, SearchLimits::depth(3)),
+            Box::new(SilentReporter),
+        );
+        assert_eq!(pool.wait().unwrap().depth, 3);
+    }
+
+    #[test]
+    fn no_legal_moves() {
+        let tt = Arc::new(TT::new(16));
+        let shared = Arc::new(SharedContext::new());
+        let mut pool = ThreadPool::new(2);
+
+        // Checkmated, the pool still answers and is ready for the next search
+        let mut mated = job(&tt, &shared, SearchLimits::depth(3));
+        mated.board = "7k/6Q1/6K1/8/8/8/8/8 b - - 0 1".parse().unwrap();
+        mated.game_history = vec![mated.board.hash()];
+        pool.start(mated, Box::new(SilentReporter));
+        assert_eq!(pool.wait().unwrap().best_move, None);
+        shared.store_stop(false);
+        shared.clear_search();
+
+        pool.start(
+            job(&tt, &shared, SearchLimits::depth(3)),
+            Box::new(SilentReporter),
+        );
+        assert!(pool.wait().unwrap().best_move.is_some());
+    }
+
+    #[test]
+    fn failed_search() {
+        let tt = Arc::new(TT::new(16));
+        let shared = Arc::new(SharedContext::new());
+        let mut pool = ThreadPool::new(3);
+
+        // The main thread failing still ends the search, the helpers included
+        pool.start(
+            job(&tt, &shared, SearchLimits::infinite()),
+            Box::new(PanickingReporter),
+        );
+        assert_eq!(pool.wait(), None);
+        assert!(!pool.is_searching());
+        assert_eq!(pool.failures(), 1);
+        shared.store_stop(false);
+        shared.clear_search();
+
+        // ... and the pool keeps working
+        pool.start(
+            job(&tt, &shared, SearchLimits::depth(3)),
+            Box::new(SilentReporter),
+        );
+        assert_eq!(pool.wait().unwrap().depth, 3);
+        assert_eq!(pool.failures(), 1);
+    }
+}
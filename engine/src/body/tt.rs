@@ -1,6 +1,13 @@
 use crate::definitions::{NOMOVE, TB_LOSS_IN_PLY, TB_WIN_IN_PLY};
 
 use cozy_chess::{Move, Piece, Square};
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
@@ -123,54 +130,88 @@ impl From<TTEntry> for u64 {
     }
 }
 
+// Entries sharing an index, sized to fit a cache line
+const BUCKET_SIZE: usize = 4;
+
+// A saved table starts with these, followed by its epoch and bucket count.
+// The version goes up whenever the entries are laid out differently.
+const FILE_MAGIC: &[u8; 8] = b"SVARTTT\0";
+const FILE_VERSION: u32 = 1;
+
+// Slots looked at for the hashfull estimate
+const HASHFULL_SAMPLE: usize = 1000;
+
+// An entry next to its full key. The key is stored XORed with the entry, so an entry
+// torn by two threads writing at once won't verify and is taken for a miss.
+#[derive(Default)]
+struct Slot {
+    key: AtomicU64,
+    data: AtomicU64,
+}
+
+impl Slot {
+    fn load(&self) -> (u64, u64) {
+        let data = self.data.load(Ordering::Relaxed);
+        (self.key.load(Ordering::Relaxed) ^ data, data)
+    }
+
+    fn store(&self, key: u64, data: u64) {
+        self.key.store(key ^ data, Ordering::Relaxed);
+        self.data.store(data, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+#[repr(C, align(64))]
+struct Bucket {
+    slots: [Slot; BUCKET_SIZE],
+}
+
 pub struct TT {
-    pub entries: Vec<AtomicU64>,
+    buckets: Buckets,
     pub epoch: u8,
 }
 
 impl TT {
     pub fn new(mb: u64) -> Self {
         let hash_size = mb * 1024 * 1024;
-        let size = hash_size / std::mem::size_of::<TTEntry>() as u64;
-        let mut entries = Vec::with_capacity(size as usize);
+        let size = hash_size / std::mem::size_of::<Bucket>() as u64;
 
-        for _ in 0..size {
-            entries.push(AtomicU64::new(0));
+        Self {
+            buckets: Buckets::zeroed(size as usize),
+            epoch: 0,
         }
-
-        Self { entries, epoch: 0 }
     }
 
     #[must_use]
     pub fn index(&self, key: u64) -> usize {
         // Cool hack Cosmo taught me
         let key = key as u128;
-        let len = self.entries.len() as u128;
+        let len = self.buckets.len() as u128;
         ((key * len) >> 64) as usize
     }
 
     #[must_use]
-    pub fn probe(&self, key: u64) -> TTEntry {
-        let atomic = &self.entries[self.index(key)];
-        let entry = atomic.load(Ordering::Relaxed);
-
-        TTEntry::from(entry)
+    pub fn probe(&self, key: u64) -> Option<TTEntry> {
+        self.buckets[self.index(key)]
+            .slots
+            .iter()
+            .map(Slot::load)
+            .find(|&(slot_key, data)| slot_key == key && data != 0)
+            .map(|(_, data)| TTEntry::from(data))
     }
 
     pub fn age(&mut self) {
-        // Cap at 63 for wrapping into 6 bits
-        const EPOCH_MAX: u8 = 63;
-
         if self.epoch == EPOCH_MAX {
             self.epoch = 0;
 
-            self.entries.iter_mut().for_each(|a| {
-                let entry = a.load(Ordering::Relaxed);
-                let mut entry = TTEntry::from(entry);
+            self.slots().for_each(|slot| {
+                let (key, data) = slot.load();
+                let mut entry = TTEntry::from(data);
 
                 entry.age_flag = AgeAndFlag::new(0, entry.age_flag.flag());
 
-                a.store(entry.into(), Ordering::Relaxed);
+                slot.store(key, entry.into());
             })
         }
 
@@ -186,11 +227,9 @@ impl TT {
         flag: TTFlag,
         ply: usize,
     ) {
-        let target_index = self.index(key);
-        let target_atomic = &self.entries[target_index];
-        let mut target: TTEntry = target_atomic.load(Ordering::Relaxed).into();
+        let bucket = &self.buckets[self.index(key)];
 
-        let entry = TTEntry {
+        let mut entry = TTEntry {
             key: key as u16,
             mv: PackedMove::new(mv),
             score: score_to_tt(score, ply),
@@ -198,21 +237,131 @@ impl TT {
             age_flag: AgeAndFlag::new(self.epoch, flag),
         };
 
-        // Only replace entries of similar or higher quality
-        if entry.quality() >= target.quality() {
-            let positions_differ = target.key != entry.key;
+        // The position's own slot, only replaced by entries of similar or higher quality
+        if let Some(slot) = bucket.slots.iter().find(|slot| slot.load().0 == key) {
+            let target = TTEntry::from(slot.load().1);
 
-            target.key = entry.key;
-            target.score = entry.score;
-            target.depth = entry.depth;
-            target.age_flag = entry.age_flag;
+            if entry.quality() >= target.quality() {
+                // Do not overwrite the move if there was no new best move
+                if mv.is_none() {
+                    entry.mv = target.mv;
+                }
 
-            // Do not overwrite the move if there was no new best move
-            if mv.is_some() || positions_differ {
-                target.mv = entry.mv;
+                slot.store(key, entry.into());
             }
 
-            target_atomic.store(target.into(), Ordering::Relaxed);
+            return;
+        }
+
+        // Otherwise the least useful entry makes way, empty slots being the least useful of all
+        let slot = bucket
+            .slots
+            .iter()
+            .min_by_key(|slot| TTEntry::from(slot.load().1).quality())
+            .unwrap();
+        slot.store(key, entry.into());
+    }
+
+    // Writes every entry with its full key, returns how many there were
+    pub fn save(&self, path: &Path) -> io::Result<usize> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(FILE_MAGIC)?;
+        file.write_all(&FILE_VERSION.to_le_bytes())?;
+        file.write_all(&[self.epoch])?;
+        file.write_all(&(self.buckets.len() as u64).to_le_bytes())?;
+
+        let mut count = 0;
+        for (key, data) in self.slots().map(Slot::load).filter(|&(_, data)| data != 0) {
+            file.write_all(&key.to_le_bytes())?;
+            file.write_all(&data.to_le_bytes())?;
+            count += 1;
+        }
+
+        file.flush()?;
+        Ok(count)
+    }
+
+    // Replaces the table with a saved one, returns how many entries were read. Every entry
+    // is indexed anew, so a file from a table of another size fills this one just as well.
+    pub fn load(&mut self, path: &Path, threads: usize) -> io::Result<usize> {
+        let invalid = |error: &str| io::Error::new(ErrorKind::InvalidData, error.to_string());
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if &magic != FILE_MAGIC {
+            return Err(invalid("not a saved hash table"));
+        }
+
+        let mut header = [0; 13];
+        file.read_exact(&mut header)?;
+        let version = u32::from_le_bytes(header[..4].try_into().unwrap());
+        if version != FILE_VERSION {
+            return Err(invalid(&format!(
+                "hash file version {version}, expected {FILE_VERSION}"
+            )));
+        }
+
+        let epoch = header[4];
+        if epoch > EPOCH_MAX {
+            return Err(invalid("epoch out of range"));
+        }
+
+        // The table it came from couldn't have held more than this
+        let buckets = u64::from_le_bytes(header[5..].try_into().unwrap());
+        let capacity = buckets.saturating_mul(BUCKET_SIZE as u64);
+
+        // Check everything before touching the table
+        let mut entries = vec![];
+        let mut buf = [0; 16];
+        loop {
+            match file.read_exact(&mut buf) {
+                Ok(()) => (),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+
+            let key = u64::from_le_bytes(buf[..8].try_into().unwrap());
+            let data = u64::from_le_bytes(buf[8..].try_into().unwrap());
+            if !valid_entry(key, data, epoch) {
+                return Err(invalid("corrupt entry"));
+            }
+            if entries.len() as u64 == capacity {
+                return Err(invalid("more entries than the table had room for"));
+            }
+
+            entries.push((key, data));
+        }
+
+        self.reset(threads);
+        self.epoch = epoch;
+        for &(key, data) in &entries {
+            self.insert(key, data);
+        }
+
+        Ok(entries.len())
+    }
+
+    // Puts a saved entry back, where it only takes the place of less useful ones
+    fn insert(&self, key: u64, data: u64) {
+        let bucket = &self.buckets[self.index(key)];
+        let quality = TTEntry::from(data).quality();
+
+        let slot = bucket
+            .slots
+            .iter()
+            .find(|slot| slot.load().0 == key)
+            .unwrap_or_else(|| {
+                bucket
+                    .slots
+                    .iter()
+                    .min_by_key(|slot| TTEntry::from(slot.load().1).quality())
+                    .unwrap()
+            });
+
+        let (slot_key, slot_data) = slot.load();
+        if slot_data == 0 || slot_key == key || quality > TTEntry::from(slot_data).quality() {
+            slot.store(key, data);
         }
     }
 
@@ -222,16 +371,118 @@ impl TT {
             use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
 
             let index = self.index(key);
-            let entry = &self.entries[index];
+            let bucket = &self.buckets[index];
 
-            _mm_prefetch((entry as *const AtomicU64).cast::<i8>(), _MM_HINT_T0);
+            _mm_prefetch((bucket as *const Bucket).cast::<i8>(), _MM_HINT_T0);
         }
     }
 
-    pub fn reset(&mut self) {
-        self.entries.iter().for_each(|a| {
-            a.store(0, Ordering::Relaxed);
-        })
+    // Permille of the table filled by the current search, going by the first thousand slots
+    #[must_use]
+    pub fn hashfull(&self) -> usize {
+        self.slots()
+            .take(HASHFULL_SAMPLE)
+            .filter(|slot| {
+                let data = slot.load().1;
+                data != 0 && TTEntry::from(data).age_flag.age() == self.epoch
+            })
+            .count()
+            * 1000
+            / HASHFULL_SAMPLE
+    }
+
+    // Empties the table in place, split over `threads` threads
+    pub fn reset(&mut self, threads: usize) {
+        self.epoch = 0;
+        let chunk_size = self.buckets.len().div_ceil(threads.max(1)).max(1);
+
+        std::thread::scope(|s| {
+            for chunk in self.buckets.chunks_mut(chunk_size) {
+                s.spawn(|| chunk.fill_with(Bucket::default));
+            }
+        });
+    }
+
+    fn slots(&self) -> impl Iterator<Item = &Slot> {
+        self.buckets.iter().flat_map(|bucket| bucket.slots.iter())
+    }
+}
+
+// Cap at 63 for wrapping into 6 bits
+const EPOCH_MAX: u8 = 63;
+
+// A stored entry as it could have come from `TT::store`, no newer than the table's epoch
+fn valid_entry(key: u64, data: u64, epoch: u8) -> bool {
+    let entry = TTEntry::from(data);
+    let promotion = (entry.mv.0 >> 12) & 0b111;
+
+    data != 0
+        && entry.key == key as u16
+        && entry.age_flag.flag() != TTFlag::None
+        && entry.age_flag.age() <= epoch
+        && (promotion == 0 || promotion >= 0b100)
+}
+
+// The buckets of the table, zeroed in a single allocation. A zeroed allocation at the default
+// alignment comes straight from the OS, which only hands out the pages once they're used.
+// Asking for cache line alignment would have the allocator write every byte itself instead,
+// so this takes one line more than needed and starts the table at the first line boundary.
+struct Buckets {
+    raw: NonNull<u8>,
+    layout: Layout,
+    len: usize,
+}
+
+// SAFETY: The buckets are owned like a `Vec` owns its elements, and a bucket is nothing but atomics.
+unsafe impl Send for Buckets {}
+unsafe impl Sync for Buckets {}
+
+impl Buckets {
+    fn zeroed(len: usize) -> Self {
+        let size = len * std::mem::size_of::<Bucket>() + std::mem::align_of::<Bucket>();
+        let layout = Layout::from_size_align(size, std::mem::align_of::<u64>())
+            .expect("the TT fits in memory");
+
+        // SAFETY: The layout is never zero sized.
+        let raw = unsafe { alloc::alloc_zeroed(layout) };
+        let Some(raw) = NonNull::new(raw) else {
+            alloc::handle_alloc_error(layout);
+        };
+
+        Self { raw, layout, len }
+    }
+
+    fn start(&self) -> *mut Bucket {
+        let offset = self
+            .raw
+            .as_ptr()
+            .align_offset(std::mem::align_of::<Bucket>());
+
+        // SAFETY: The extra line leaves room for the offset.
+        unsafe { self.raw.as_ptr().add(offset).cast() }
+    }
+}
+
+impl Deref for Buckets {
+    type Target = [Bucket];
+
+    fn deref(&self) -> &[Bucket] {
+        // SAFETY: `len` aligned buckets fit after the start, and all zeroes is a valid bucket.
+        unsafe { slice::from_raw_parts(self.start(), self.len) }
+    }
+}
+
+impl DerefMut for Buckets {
+    fn deref_mut(&mut self) -> &mut [Bucket] {
+        // SAFETY: As above, and `&mut self` makes sure no one else looks at them.
+        unsafe { slice::from_raw_parts_mut(self.start(), self.len) }
+    }
+}
+
+impl Drop for Buckets {
+    fn drop(&mut self) {
+        // SAFETY: Allocated with this very layout, and buckets need no dropping.
+        unsafe { alloc::dealloc(self.raw.as_ptr(), self.layout) }
     }
 }
 
@@ -258,6 +509,7 @@ pub fn score_from_tt(score: i16, ply: usize) -> i16 {
 }
 
 const _TT_TEST: () = assert!(std::mem::size_of::<TTEntry>() == 8);
+const _BUCKET_TEST: () = assert!(std::mem::size_of::<Bucket>() == 64);
 
 #[cfg(test)]
 mod tests {
@@ -272,12 +524,19 @@ mod tests {
             promotion: None,
         };
         tt.store(5, Some(mv), 1, 3, TTFlag::UpperBound, 22);
-        assert_eq!(tt.probe(5).score, 1);
-
-        tt.reset();
-        tt.entries.iter().for_each(|e| {
-            let e = e.load(Ordering::Relaxed);
+        assert_eq!(tt.probe(5).unwrap().score, 1);
+        tt.age();
+
+        // The table is emptied where it is
+        let buckets = tt.buckets.as_ptr();
+        tt.reset(3);
+        assert_eq!(tt.buckets.as_ptr(), buckets);
+        assert_eq!(tt.epoch, 0);
+        assert!(tt.probe(5).is_none());
+        tt.slots().for_each(|slot| {
+            let (key, e) = slot.load();
             let e = TTEntry::from(e);
+            assert_eq!(key, 0);
 
             assert_eq!(e.score, 0);
             assert_eq!(e.age_flag, AgeAndFlag(0));
@@ -287,6 +546,74 @@ mod tests {
         });
     }
 
+    #[test]
+    fn hashfull() {
+        let mut tt = TT::new(1);
+        assert_eq!(tt.hashfull(), 0);
+
+        // Only the sampled buckets at the very start count
+        let len = tt.buckets.len() as u128;
+        let bucket_key = |i: u64| ((u128::from(i) << 64).div_ceil(len)) as u64;
+        for i in 0..HASHFULL_SAMPLE as u64 / 8 {
+            let key = bucket_key(i);
+            assert_eq!(tt.index(key), i as usize);
+            for j in 0..2 {
+                tt.store(key + j, None, 0, 1, TTFlag::Exact, 0);
+            }
+        }
+        assert_eq!(tt.hashfull(), 250);
+
+        // Entries of earlier searches are there to be replaced
+        tt.age();
+        assert_eq!(tt.hashfull(), 0);
+        tt.store(bucket_key(0), None, 0, 1, TTFlag::Exact, 0);
+        assert_eq!(tt.hashfull(), 1);
+    }
+
+    #[test]
+    fn buckets() {
+        let mut tt = TT::new(1);
+        let mv = Move {
+            from: Square::E2,
+            to: Square::E4,
+            promotion: None,
+        };
+
+        // Keys only differing in the low bits share a bucket
+        let key = |i: u64| 0xABCD_0000_0000_0000 | i << 16 | 0x1234;
+        assert!((0..8).all(|i| tt.index(key(i)) == tt.index(key(0))));
+
+        for i in 0..BUCKET_SIZE as u64 {
+            tt.store(key(i), Some(mv), 10, 10 - i as u8, TTFlag::Exact, 0);
+        }
+        assert!((0..BUCKET_SIZE as u64).all(|i| tt.probe(key(i)).is_some()));
+
+        // The same 16 bits of key, but not the same position
+        assert!(tt.probe(key(BUCKET_SIZE as u64)).is_none());
+
+        // The shallowest entry makes way
+        tt.store(key(8), None, 20, 5, TTFlag::LowerBound, 0);
+        assert!(tt.probe(key(BUCKET_SIZE as u64 - 1)).is_none());
+        assert_eq!(tt.probe(key(8)).unwrap().score, 20);
+
+        // A shallower search of a stored position leaves it be, a deeper one keeps the move
+        tt.store(key(0), None, -50, 1, TTFlag::UpperBound, 0);
+        assert_eq!(tt.probe(key(0)).unwrap().score, 10);
+        tt.store(key(0), None, -50, 12, TTFlag::UpperBound, 0);
+        let entry = tt.probe(key(0)).unwrap();
+        assert_eq!((entry.score, entry.depth), (-50, 12));
+        assert_eq!(entry.mv.unpack(), mv);
+
+        // New entries outlast old ones, however shallow
+        for _ in 0..10 {
+            tt.age();
+        }
+        tt.store(key(9), None, 0, 1, TTFlag::Exact, 0);
+        tt.store(key(10), None, 0, 1, TTFlag::Exact, 0);
+        assert!(tt.probe(key(9)).is_some() && tt.probe(key(10)).is_some());
+        assert!(tt.probe(key(8)).is_none() && tt.probe(key(2)).is_none());
+    }
+
     #[test]
     fn packed_moves() {
         let mv = Move {
@@ -330,6 +657,81 @@ mod tests {
         assert_eq!(packed.unpack(), mv);
     }
 
+    #[test]
+    fn saved_tables() {
+        let path = std::env::temp_dir().join(format!("svart-tt-{}.hash", std::process::id()));
+        let mv = Move {
+            from: Square::G1,
+            to: Square::F3,
+            promotion: None,
+        };
+        let keys: Vec<u64> = (1..=20_000u64)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+            .collect();
+
+        let mut tt = TT::new(2);
+        tt.age();
+        tt.age();
+        for (i, &key) in keys.iter().enumerate() {
+            tt.store(key, Some(mv), i as i16, (i % 60) as u8, TTFlag::Exact, 0);
+        }
+        let stored = keys.iter().filter(|&&key| tt.probe(key).is_some()).count();
+        assert_eq!(tt.save(&path).unwrap(), stored);
+
+        let entries = |tt: &TT| -> Vec<Option<u64>> {
+            keys.iter()
+                .map(|&key| tt.probe(key).map(u64::from))
+                .collect()
+        };
+
+        // A table of the same size or bigger gets back everything
+        for mb in [2, 4] {
+            let mut loaded = TT::new(mb);
+            assert_eq!(loaded.load(&path, 3).unwrap(), stored);
+            assert_eq!(loaded.epoch, tt.epoch);
+            assert_eq!(entries(&loaded), entries(&tt));
+        }
+
+        // A smaller one keeps what fits
+        let mut smaller = TT::new(1);
+        smaller.load(&path, 1).unwrap();
+        let kept = entries(&smaller);
+        assert!(kept.iter().flatten().count() > stored * 3 / 4);
+        assert!(kept
+            .iter()
+            .zip(entries(&tt))
+            .all(|(kept, original)| kept.is_none() || *kept == original));
+
+        // Other files are turned away without touching the table
+        let mut header = FILE_MAGIC.to_vec();
+        header.extend((FILE_VERSION + 1).to_le_bytes());
+        header.extend([2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let mut corrupt = FILE_MAGIC.to_vec();
+        corrupt.extend(FILE_VERSION.to_le_bytes());
+        corrupt.extend([2, 0, 0, 0, 0, 0, 0, 0, 0]);
+        corrupt.extend(keys[0].to_le_bytes());
+        corrupt.extend(u64::MAX.to_le_bytes());
+
+        // Entries from an epoch the table claims not to have reached yet
+        let saved = std::fs::read(&path).unwrap();
+        let mut future = saved.clone();
+        future[12] = tt.epoch - 1;
+
+        // More entries than the single bucket it claims to come from
+        let mut overfull = saved[..13].to_vec();
+        overfull.extend(1u64.to_le_bytes());
+        overfull.extend(&saved[21..21 + 16 * (BUCKET_SIZE + 1)]);
+
+        for contents in [b"no table".to_vec(), header, corrupt, future, overfull] {
+            std::fs::write(&path, contents).unwrap();
+            let error = smaller.load(&path, 1).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+            assert_eq!(entries(&smaller), kept);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn age_flag() {
         let entry = TTEntry {
@@ -1,3 +1,5 @@
+use engine::uci::handler::parse_fen;
+
 fn main() {
     #![warn(clippy::all, clippy::pedantic, clippy::nursery, clippy::cargo)]
     let binding = std::env::args().nth(1);
@@ -7,5 +9,24 @@ fn main() {
         return;
     }
 
+    // perft <depth> [fen]
+    if arg == Some("perft") {
+        let depth = std::env::args()
+            .nth(2)
+            .and_then(|d| d.parse().ok())
+            .unwrap_or(5);
+        let fen = std::env::args().skip(3).collect::<Vec<_>>().join(" ");
+        // Positions that aren't valid standard chess are read as Chess960
+        let chess960 = !fen.is_empty() && parse_fen(&fen, false).is_err();
+        let board = if fen.is_empty() {
+            cozy_chess::Board::default()
+        } else {
+            parse_fen(&fen, chess960).expect("Invalid FEN")
+        };
+
+        engine::body::perft::run(&board, depth, true, chess960);
+        return;
+    }
+
     engine::uci::handler::uci_loop();
 }
@@ -1,7 +1,11 @@
 use std::time::Instant;
 
-use super::handler::SearchType;
-use crate::body::{nnue::inference::NNUEState, search::Search, tt::TT};
+use super::handler::SearchLimits;
+use crate::body::{
+    nnue::inference::NNUEState,
+    search::{Search, SharedContext},
+    tt::TT,
+};
 use cozy_chess::Board;
 
 const FENS: [&str; 62] = [
@@ -71,26 +75,29 @@ const FENS: [&str; 62] = [
 
 pub fn bench() {
     let mut tt = TT::new(16);
+    let shared = SharedContext::new();
     let b = Board::default();
     let nnue = NNUEState::from_board(&b);
     let history = crate::body::history::History::new();
 
-    let mut search = Search::new(&tt, &nnue, &history, &vec![b.hash()]);
+    let mut game_history = vec![b.hash()];
     let mut tot_nodes = 0;
     let mut tot_time = 0;
 
     for fen in FENS.iter() {
         let board = Board::from_fen(fen, false).unwrap();
+        let mut search = Search::new(&tt, &shared, &nnue, &history, &game_history);
         search.nnue.refresh(&board);
 
         let timer = Instant::now();
-        search.iterative_deepening::<true>(&board, SearchType::Depth(12), false);
+        search.iterative_deepening::<true>(&board, SearchLimits::depth(12));
         tot_time += timer.elapsed().as_millis();
         tot_nodes += search.info.nodes;
 
         search.game_reset();
-        tt.reset();
-        search = Search::new(&tt, &nnue, &history, &vec![board.hash()]);
+        drop(search);
+        tt.reset(1);
+        game_history = vec![board.hash()];
     }
 
     println!(
@@ -1,27 +1,105 @@
-use super::timeman::time_for_move;
+use super::{
+    parser::{Tokens, UciError},
+    reporter::{PrettyReporter, Reporter, UciReporter},
+    timeman::{time_for_move, TimeOptions},
+};
 
 use crate::body::{
-    history::History,
     nnue::inference::NNUEState,
-    search::{clear_nodes, store_stop, Search},
-    tt::TT,
+    perft,
+    search::{SearchResult, SharedContext},
+    thread_pool::{SearchJob, ThreadPool},
+    tt::{TTFlag, TT},
+};
+use crate::definitions::{MATE, MAX_MOVES_POSITION, TIME_OVERHEAD};
+
+use cozy_chess::{Board, Color, FenParseError, Move, Piece, Rank, Square};
+use std::collections::VecDeque;
+use std::ops::{ControlFlow, RangeInclusive};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{
+    mpsc::{self, Receiver, RecvTimeoutError},
+    Arc,
 };
-use crate::definitions::MATE;
+use std::time::Duration;
 
-use cozy_chess::{Board, Color, Move, Piece, Square};
+// Where Save Hash and Load Hash go unless told otherwise, relative to the working directory
+const DEFAULT_HASH_FILE: &str = "svart.hash";
 
+// The clock of the side to move, in milliseconds
 #[derive(Debug, PartialEq, Copy, Clone)]
-pub enum SearchType {
-    // opt_time and maxtime
-    Time(u64, u64),
-    Nodes(u64),
-    Depth(usize),
-    Infinite,
+pub struct Clock {
+    pub time: u64,
+    pub inc: u64,
+    pub moves_to_go: Option<u8>,
+}
+
+// Any combination of limits can be given, the search stops at whichever is reached first
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub struct SearchLimits {
+    pub depth: Option<usize>,
+    pub nodes: Option<u64>,
+    // milliseconds
+    pub movetime: Option<u64>,
+    pub clock: Option<Clock>,
+    // mate in moves
+    pub mate: Option<usize>,
+    // bestmove is held back until stop
+    pub infinite: bool,
+}
+
+impl SearchLimits {
+    pub fn depth(depth: usize) -> Self {
+        Self {
+            depth: Some(depth),
+            ..Self::default()
+        }
+    }
+
+    pub fn nodes(nodes: u64) -> Self {
+        Self {
+            nodes: Some(nodes),
+            ..Self::default()
+        }
+    }
+
+    pub fn infinite() -> Self {
+        Self {
+            infinite: true,
+            ..Self::default()
+        }
+    }
+
+    // Optimum and maximum time, the tighter of movetime and the clock
+    pub fn time_bounds(&self, ponder: bool, options: &TimeOptions) -> Option<(u64, u64)> {
+        let movetime = self.movetime.map(|t| (t, t));
+        let clock = self.clock.map(|c| {
+            let (mut opt, max) = time_for_move(c.time, c.inc, c.moves_to_go, options);
+
+            // Pondering makes up for some of the time spent
+            if ponder {
+                opt = opt.saturating_add(opt / 4).min(max);
+            }
+
+            (opt, max)
+        });
+
+        match (movetime, clock) {
+            (Some((mt_opt, mt_max)), Some((opt, max))) => Some((mt_opt.min(opt), mt_max.min(max))),
+            (movetime, clock) => movetime.or(clock),
+        }
+    }
 }
 
 struct UCIOptions {
     hash: u64,
     threads: u32,
+    multipv: usize,
+    ponder: bool,
+    chess960: bool,
+    time: TimeOptions,
+    hash_file: String,
 }
 
 impl UCIOptions {
@@ -29,6 +107,11 @@ impl UCIOptions {
         Self {
             hash: 16,
             threads: 1,
+            multipv: 1,
+            ponder: false,
+            chess960: false,
+            time: TimeOptions::default(),
+            hash_file: DEFAULT_HASH_FILE.to_string(),
         }
     }
 }
@@ -47,302 +130,472 @@ fn id() {
 fn options() {
     println!("option name Hash type spin default 16 min 1 max 1000000");
     println!("option name Threads type spin default 1 min 1 max 1024");
+    println!("option name MultiPV type spin default 1 min 1 max {MAX_MOVES_POSITION}");
+    println!("option name Ponder type check default false");
+    println!("option name UCI_Chess960 type check default false");
+    println!("option name Move Overhead type spin default {TIME_OVERHEAD} min 0 max 5000");
+    println!("option name Optimum Time Scale type spin default 100 min 10 max 500");
+    println!("option name Maximum Time Scale type spin default 100 min 10 max 500");
+    println!("option name nodestime type spin default 0 min 0 max 10000");
+    println!("option name Hash File type string default {DEFAULT_HASH_FILE}");
+    println!("option name Save Hash type button");
+    println!("option name Load Hash type button");
+    println!("option name Clear Hash type button");
 }
 
 pub fn uci_loop() {
-    // This should (and will be) made into an object in and of itself later
-    let mut uci_set = false;
-    let mut board_set = false;
-    let mut board = Board::default();
-    let mut stored_input: Option<String> = None;
-
-    let mut uci_options = UCIOptions::default();
-    let mut tt = TT::new(uci_options.hash);
-
-    let mut nnue = NNUEState::from_board(&board);
-    let mut history = History::new();
-    let mut game_history = vec![];
+    let mut handler = Handler::new();
+    let input = spawn_input_thread();
 
     loop {
-        let line = if let Some(si) = stored_input.clone() {
-            stored_input = None;
-            si
+        // With commands waiting on the search, keep checking whether it's over
+        let line = if handler.has_queued() {
+            match input.recv_timeout(Duration::from_millis(10)) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    if handler.run_queued().is_break() {
+                        break;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
         } else {
-            read_input().unwrap()
+            let Ok(line) = input.recv() else {
+                break;
+            };
+            line
+        };
+
+        match handler.execute(&line) {
+            Ok(ControlFlow::Continue(())) => (),
+            Ok(ControlFlow::Break(())) => break,
+            Err(e) => println!("info string {e}"),
+        }
+    }
+}
+
+// Everything which carries over from one command to the next
+pub struct Handler {
+    uci_set: bool,
+    board_set: bool,
+    board: Board,
+    uci_options: UCIOptions,
+    tt: Arc<TT>,
+    shared: Arc<SharedContext>,
+    nnue: Box<NNUEState>,
+    game_history: Vec<u64>,
+    pool: ThreadPool,
+    // Commands which came in during a search, in order
+    queued: VecDeque<String>,
+    // The running search only ends on stop
+    infinite: bool,
+}
+
+impl Handler {
+    pub fn new() -> Self {
+        let uci_options = UCIOptions::default();
+        let board = Board::default();
+
+        Handler {
+            uci_set: false,
+            board_set: false,
+            tt: Arc::new(TT::new(uci_options.hash)),
+            shared: Arc::new(SharedContext::new()),
+            nnue: NNUEState::from_board(&board),
+            game_history: vec![],
+            pool: ThreadPool::new(uci_options.threads as usize),
+            queued: VecDeque::new(),
+            infinite: false,
+            board,
+            uci_options,
+        }
+    }
+
+    // Takes a single command, breaks on quit. Only these are handled while a search is
+    // running, every other command is queued up until it's over so input keeps being read.
+    pub fn execute(&mut self, line: &str) -> Result<ControlFlow<()>, UciError> {
+        let tokens = Tokens::new(line);
+        let Some(command) = tokens.command() else {
+            return Ok(ControlFlow::Continue(()));
         };
 
-        let words: Vec<&str> = line.split_whitespace().collect();
-        if words.is_empty() {
-            continue;
+        match command {
+            "isready" => {
+                // Ready once everything sent before is done, or right away during a search
+                if self.run_queued().is_break() {
+                    return Ok(ControlFlow::Break(()));
+                }
+                println!("readyok");
+                return Ok(ControlFlow::Continue(()));
+            }
+            "stop" | "ponderhit" => {
+                // Meant for the last search asked for, which may still be waiting its turn
+                if self.queued.is_empty() {
+                    self.control(command);
+                } else {
+                    // Unless the one in the way would never end by itself
+                    let endless = self.infinite || self.shared.load_ponder();
+                    if command == "stop" && endless && self.pool.is_searching() {
+                        self.control(command);
+                    }
+                    self.queued.push_back(line.to_string());
+                }
+                return Ok(self.run_queued());
+            }
+            "quit" => {
+                self.shared.store_stop(true);
+                self.finish_search();
+                return Ok(ControlFlow::Break(()));
+            }
+            _ => (),
         }
 
-        if !uci_set {
-            match words[0] {
+        if self.run_queued().is_break() {
+            return Ok(ControlFlow::Break(()));
+        }
+
+        if self.pool.is_searching() {
+            self.queued.push_back(line.to_string());
+            return Ok(ControlFlow::Continue(()));
+        }
+
+        self.run(&tokens)
+    }
+
+    pub fn has_queued(&self) -> bool {
+        !self.queued.is_empty()
+    }
+
+    // Runs the queued commands once the search is over, up to the next one starting a search.
+    // A stop or ponderhit next in line goes to that search right away. Nobody is waiting on
+    // the result of the others anymore, so errors go straight out.
+    pub fn run_queued(&mut self) -> ControlFlow<()> {
+        while let Some(line) = self.queued.front().cloned() {
+            let tokens = Tokens::new(&line);
+            let control = matches!(tokens.command(), Some("stop" | "ponderhit"));
+            if !control && self.pool.is_searching() {
+                break;
+            }
+
+            self.queued.pop_front();
+            match tokens.command() {
+                Some(command) if control => self.control(command),
+                _ => match self.run(&tokens) {
+                    Ok(ControlFlow::Continue(())) => (),
+                    Ok(ControlFlow::Break(())) => return ControlFlow::Break(()),
+                    Err(e) => println!("info string {e}"),
+                },
+            }
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    // Stop or ponderhit, for the running search if there is one
+    fn control(&mut self, command: &str) {
+        if command == "ponderhit" {
+            self.shared.store_ponder(false);
+            return;
+        }
+
+        // A stop arriving after the search is over mustn't cut the next one short
+        if self.pool.is_searching() {
+            self.shared.store_stop(true);
+        }
+        self.finish_search();
+    }
+
+    // Runs a command which isn't handled during a search
+    fn run(&mut self, tokens: &Tokens) -> Result<ControlFlow<()>, UciError> {
+        let Some(command) = tokens.command() else {
+            return Ok(ControlFlow::Continue(()));
+        };
+
+        // The result of a search which ended by itself still needs collecting
+        self.finish_search();
+
+        if !self.uci_set {
+            match command {
                 "uci" => {
                     id();
                     options();
                     println!("uciok");
-                    uci_set = true;
-                }
-                "quit" => {
-                    break;
+                    self.uci_set = true;
                 }
                 "bench" => {
                     super::bench::bench();
-                    break;
+                    return Ok(ControlFlow::Break(()));
                 }
-                "position" => set_position(
-                    &mut board,
-                    &mut nnue,
-                    &mut game_history,
-                    &mut board_set,
-                    words,
-                ),
+                "position" => self.set_position(tokens)?,
                 "go" => {
-                    let mut search = Search::new(&tt, &nnue, &history, &game_history);
-
-                    if board_set {
-                        search.iterative_deepening::<true>(&board, SearchType::Infinite, true);
-                    } else {
-                        search.iterative_deepening::<true>(
-                            &Board::default(),
-                            SearchType::Infinite,
-                            true,
-                        );
-                    }
+                    let reporter = PrettyReporter {
+                        multipv: self.uci_options.multipv > 1,
+                    };
+                    self.go(SearchLimits::infinite(), vec![], Box::new(reporter));
                 }
-                _ => (),
+                _ => return Err(UciError::UnknownCommand(command.to_string())),
             }
         } else {
-            match words[0] {
+            match command {
                 "uci" => {
                     id();
                     options();
                     println!("uciok");
-
-                    continue;
-                }
-                "isready" => {
-                    println!("readyok");
-                    continue;
                 }
                 "ucinewgame" => {
-                    board = Board::default();
-                    tt = TT::new(uci_options.hash);
-                    nnue.refresh(&board);
-                    history = History::new();
-                    game_history = vec![board.hash()];
-                    board_set = true;
-
-                    continue;
+                    self.reset();
+                    self.board_set = true;
                 }
-                "setoption" => {
-                    if words[1] == "name" && words[2] == "Hash" && words[3] == "value" {
-                        if let Ok(s) = words[4].parse::<u64>() {
-                            if s > 1_000_000 {
-                                continue;
-                            }
-
-                            uci_options.hash = s;
-                            tt = TT::new(uci_options.hash);
-                        }
-                    }
-
-                    if words[1] == "name" && words[2] == "Threads" && words[3] == "value" {
-                        if let Ok(t) = words[4].parse::<u32>() {
-                            if !(1..=1024).contains(&t) {
-                                continue;
-                            }
-
-                            uci_options.threads = t;
-                        }
-                    }
-
-                    continue;
+                "setoption" => self.set_option(tokens)?,
+                "position" => self.set_position(tokens)?,
+                "go" if tokens.contains("perft") => {
+                    let depth = tokens
+                        .value::<usize>("perft")?
+                        .ok_or_else(|| UciError::MissingValue("perft".to_string()))?;
+                    perft::run(&self.board, depth, false, self.uci_options.chess960);
+                }
+                "divide" => {
+                    let depth = tokens
+                        .value::<usize>("divide")?
+                        .ok_or_else(|| UciError::MissingValue("divide".to_string()))?;
+                    perft::run(&self.board, depth, true, self.uci_options.chess960);
                 }
-                "position" => set_position(
-                    &mut board,
-                    &mut nnue,
-                    &mut game_history,
-                    &mut board_set,
-                    words,
-                ),
                 "go" => {
-                    if board_set {
-                        // Static depth search
-                        if words.iter().any(|&x| x == "depth") {
-                            if let Ok(d) = words
-                                [words.iter().position(|&x| x == "depth").unwrap() + 1]
-                                .parse::<usize>()
-                            {
-                                go(
-                                    &board,
-                                    SearchType::Depth(d),
-                                    &mut tt,
-                                    &nnue,
-                                    &mut history,
-                                    &game_history,
-                                    &mut stored_input,
-                                    &uci_options,
-                                );
-                            }
-                        } else if words.iter().any(|&x| x == "nodes") {
-                            if let Ok(n) = words
-                                [words.iter().position(|&x| x == "nodes").unwrap() + 1]
-                                .parse::<u64>()
-                            {
-                                go(
-                                    &board,
-                                    SearchType::Nodes(n),
-                                    &mut tt,
-                                    &nnue,
-                                    &mut history,
-                                    &game_history,
-                                    &mut stored_input,
-                                    &uci_options,
-                                );
-                            }
-                        // Infinite search
-                        } else if words.iter().any(|&x| x == "infinite") {
-                            go(
-                                &board,
-                                SearchType::Infinite,
-                                &mut tt,
-                                &nnue,
-                                &mut history,
-                                &game_history,
-                                &mut stored_input,
-                                &uci_options,
-                            );
-                        // Static time search
-                        } else if words.iter().any(|&x| x == "movetime") {
-                            if let Ok(t) = words
-                                [words.iter().position(|&x| x == "movetime").unwrap() + 1]
-                                .parse::<u64>()
-                            {
-                                go(
-                                    &board,
-                                    SearchType::Time(t, t),
-                                    &mut tt,
-                                    &nnue,
-                                    &mut history,
-                                    &game_history,
-                                    &mut stored_input,
-                                    &uci_options,
-                                );
-                            }
-                        // Time search
-                        } else if words.iter().any(|&x| x == "wtime" || x == "btime") {
-                            if board.side_to_move() == Color::White {
-                                if let Ok(t) = words
-                                    [words.iter().position(|&x| x == "wtime").unwrap() + 1]
-                                    .parse::<u64>()
-                                {
-                                    // Increment
-                                    let inc = if words.iter().any(|&x| x == "winc") {
-                                        match words
-                                            [words.iter().position(|&x| x == "winc").unwrap() + 1]
-                                            .parse::<u64>()
-                                        {
-                                            Ok(i) => i,
-                                            Err(_) => panic!("Could not parse increment"),
-                                        }
-                                    } else {
-                                        0
-                                    };
-
-                                    let mtg = if words.iter().any(|&x| x == "movestogo") {
-                                        match words[words
-                                            .iter()
-                                            .position(|&x| x == "movestogo")
-                                            .unwrap()
-                                            + 1]
-                                        .parse::<u8>()
-                                        {
-                                            Ok(m) => Some(m),
-                                            Err(_) => None,
-                                        }
-                                    } else {
-                                        None
-                                    };
-
-                                    let (opt, max) = time_for_move(t, inc, mtg);
-
-                                    go(
-                                        &board,
-                                        SearchType::Time(opt, max),
-                                        &mut tt,
-                                        &nnue,
-                                        &mut history,
-                                        &game_history,
-                                        &mut stored_input,
-                                        &uci_options,
-                                    );
-                                }
-                            } else if let Ok(t) = words
-                                [words.iter().position(|&x| x == "btime").unwrap() + 1]
-                                .parse::<u64>()
-                            {
-                                // Increment
-                                let inc = if words.iter().any(|&x| x == "binc") {
-                                    match words
-                                        [words.iter().position(|&x| x == "binc").unwrap() + 1]
-                                        .parse::<u64>()
-                                    {
-                                        Ok(i) => i,
-                                        Err(_) => panic!("Could not parse increment"),
-                                    }
-                                } else {
-                                    0
-                                };
-
-                                let mtg = if words.iter().any(|&x| x == "movestogo") {
-                                    match words
-                                        [words.iter().position(|&x| x == "movestogo").unwrap() + 1]
-                                        .parse::<u8>()
-                                    {
-                                        Ok(m) => Some(m),
-                                        Err(_) => None,
-                                    }
-                                } else {
-                                    None
-                                };
-
-                                let (opt, max) = time_for_move(t, inc, mtg);
-
-                                go(
-                                    &board,
-                                    SearchType::Time(opt, max),
-                                    &mut tt,
-                                    &nnue,
-                                    &mut history,
-                                    &game_history,
-                                    &mut stored_input,
-                                    &uci_options,
-                                );
-                            };
-                        } else {
-                            continue;
-                        }
+                    if self.board_set {
+                        let limits = parse_limits(tokens, self.board.side_to_move())?;
+                        let search_moves =
+                            parse_search_moves(&self.board, tokens, self.uci_options.chess960)?;
+
+                        // Time limits of a ponder search only apply after ponderhit
+                        self.shared.store_ponder(tokens.contains("ponder"));
+                        self.go(limits, search_moves, Box::new(UciReporter));
                     }
-                    continue;
                 }
                 "eval" => {
-                    println!("{}", nnue.evaluate(board.side_to_move()));
-                }
-                "quit" => {
-                    break;
+                    println!("{}", self.nnue.evaluate(self.board.side_to_move()));
                 }
-                _ => {
-                    continue;
+                _ => return Err(UciError::UnknownCommand(command.to_string())),
+            }
+        }
+
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn set_option(&mut self, tokens: &Tokens) -> Result<(), UciError> {
+        let name = tokens
+            .between("name", "value")
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| UciError::MissingValue("name".to_string()))?
+            .join(" ");
+        let value = tokens.after("value").map(|value| value.join(" "));
+
+        // Option names aren't case sensitive
+        match name.to_lowercase().as_str() {
+            "hash" => {
+                let hash = spin_value(&name, value, 1..=1_000_000)?;
+                if hash != self.uci_options.hash {
+                    self.uci_options.hash = hash;
+                    // The old table goes first, the two of them might not fit at once
+                    self.tt = Arc::new(TT::new(1));
+                    self.tt = Arc::new(TT::new(hash));
                 }
             }
+            "threads" => {
+                self.uci_options.threads = spin_value(&name, value, 1..=1024)?;
+                self.pool.resize(self.uci_options.threads as usize);
+            }
+            "multipv" => {
+                self.uci_options.multipv = spin_value(&name, value, 1..=MAX_MOVES_POSITION)?
+            }
+            "ponder" => self.uci_options.ponder = check_value(&name, value)?,
+            "uci_chess960" => self.uci_options.chess960 = check_value(&name, value)?,
+            "move overhead" => {
+                self.uci_options.time.move_overhead = spin_value(&name, value, 0..=5000)?
+            }
+            "optimum time scale" => {
+                self.uci_options.time.optimum_scale = spin_value(&name, value, 10..=500)?
+            }
+            "maximum time scale" => {
+                self.uci_options.time.maximum_scale = spin_value(&name, value, 10..=500)?
+            }
+            "nodestime" => self.uci_options.time.nodes_time = spin_value(&name, value, 0..=10_000)?,
+            "hash file" => {
+                self.uci_options.hash_file =
+                    value.ok_or_else(|| UciError::MissingValue(name.to_string()))?
+            }
+            "save hash" => {
+                let path = &self.uci_options.hash_file;
+                let entries = self
+                    .tt
+                    .save(Path::new(path))
+                    .map_err(|e| hash_file_error(path, e))?;
+                println!("info string saved {entries} hash entries to {path}");
+            }
+            "load hash" => {
+                let path = &self.uci_options.hash_file;
+                let entries = Arc::get_mut(&mut self.tt)
+                    .expect("no search holds on to the TT")
+                    .load(Path::new(path), self.uci_options.threads as usize)
+                    .map_err(|e| hash_file_error(path, e))?;
+                println!("info string loaded {entries} hash entries from {path}");
+            }
+            "clear hash" => {
+                let threads = self.uci_options.threads as usize;
+                self.tt_mut().reset(threads);
+            }
+            _ => return Err(UciError::UnknownOption(name)),
+        }
+
+        Ok(())
+    }
+
+    // The new position is only taken on if the whole command makes sense
+    fn set_position(&mut self, tokens: &Tokens) -> Result<(), UciError> {
+        let chess960 = self.uci_options.chess960;
+        let mut board = match tokens.args().first() {
+            Some(&"startpos") => Board::default(),
+            Some(&"fen") => {
+                let fen = tokens.between("fen", "moves").unwrap_or_default().join(" ");
+                parse_fen(&fen, chess960).map_err(|_| UciError::InvalidFen(fen))?
+            }
+            Some(other) => {
+                return Err(UciError::InvalidValue {
+                    name: "position".to_string(),
+                    value: other.to_string(),
+                })
+            }
+            None => return Err(UciError::MissingValue("position".to_string())),
+        };
+        let mut game_history = vec![board.hash()];
+
+        for word in tokens.after("moves").unwrap_or_default() {
+            let mv = word
+                .parse::<Move>()
+                .map_err(|_| UciError::InvalidMove(word.to_string()))?;
+
+            // Checked after the castling conversion, e1g1 without the rights is no king move
+            let mv = check_castling_move(&board, mv, chess960);
+            if !board.is_legal(mv) {
+                return Err(UciError::IllegalMove {
+                    mv: word.to_string(),
+                    fen: board.to_string(),
+                });
+            }
+
+            board.play_unchecked(mv);
+            game_history.push(board.hash());
+        }
+
+        self.nnue.refresh(&board);
+        self.board = board;
+        self.game_history = game_history;
+        self.board_set = true;
+
+        Ok(())
+    }
+
+    // Starts a search on the thread pool and returns right away
+    fn go(
+        &mut self,
+        limits: SearchLimits,
+        search_moves: Vec<Move>,
+        reporter: Box<dyn Reporter + Send>,
+    ) {
+        let job = SearchJob {
+            tt: Arc::clone(&self.tt),
+            shared: Arc::clone(&self.shared),
+            board: self.board.clone(),
+            game_history: self.game_history.clone(),
+            limits,
+            search_moves,
+            multipv: self.uci_options.multipv,
+            ponder: self.uci_options.ponder,
+            time_options: self.uci_options.time,
+            chess960: self.uci_options.chess960,
+        };
+
+        self.infinite = limits.infinite;
+        self.pool.start(job, reporter);
+    }
+
+    // Waits for a running search and takes back what it borrowed,
+    // the result is None if nothing was searched since the last call or the search failed
+    fn finish_search(&mut self) -> Option<SearchResult> {
+        let result = self.pool.wait();
+
+        // A search which failed leaves its flags behind all the same
+        if result.is_some() {
+            self.tt_mut().age();
         }
+        self.clear_flags();
+
+        result
+    }
+
+    // Everything a game or analysis session leaves behind, gone. Only the options stay,
+    // so the same commands after a reset always search the same nodes.
+    fn reset(&mut self) {
+        self.finish_search();
+
+        let threads = self.uci_options.threads as usize;
+        self.tt_mut().reset(threads);
+        // History and killers of every thread
+        self.pool.clear();
+        self.clear_flags();
+
+        self.board = Board::default();
+        self.nnue.refresh(&self.board);
+        self.game_history = vec![self.board.hash()];
+    }
+
+    fn clear_flags(&self) {
+        self.shared.store_stop(false);
+        self.shared.store_ponder(false);
+        self.shared.clear_search();
+    }
+
+    // Only ever called between searches
+    fn tt_mut(&mut self) -> &mut TT {
+        Arc::get_mut(&mut self.tt).expect("no search holds on to the TT")
     }
 }
 
-fn check_castling_move(board: &Board, mut mv: Move) -> Move {
-    if board.piece_on(mv.from) == Some(Piece::King) {
+impl Default for Handler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn spin_value<T>(name: &str, value: Option<String>, range: RangeInclusive<T>) -> Result<T, UciError>
+where
+    T: FromStr + PartialOrd,
+{
+    let value = value.ok_or_else(|| UciError::MissingValue(name.to_string()))?;
+    match value.parse::<T>() {
+        Ok(v) if range.contains(&v) => Ok(v),
+        _ => Err(UciError::InvalidValue {
+            name: name.to_string(),
+            value,
+        }),
+    }
+}
+
+fn check_value(name: &str, value: Option<String>) -> Result<bool, UciError> {
+    spin_value(name, value, false..=true)
+}
+
+fn hash_file_error(path: &str, error: std::io::Error) -> UciError {
+    UciError::HashFile {
+        path: path.to_string(),
+        error: error.to_string(),
+    }
+}
+
+// Standard chess castling is sent as a two square king move, while
+// Chess960 and the board itself use king-takes-rook.
+pub fn check_castling_move(board: &Board, mut mv: Move, chess960: bool) -> Move {
+    if !chess960 && board.piece_on(mv.from) == Some(Piece::King) {
         mv.to = match (mv.from, mv.to) {
             (Square::E1, Square::G1) => Square::H1,
             (Square::E8, Square::G8) => Square::H8,
@@ -354,8 +607,8 @@ fn check_castling_move(board: &Board, mut mv: Move) -> Move {
     mv
 }
 
-pub fn reverse_castling_move(board: &Board, mut mv: Move) -> Move {
-    if board.piece_on(mv.from) == Some(Piece::King) {
+pub fn reverse_castling_move(board: &Board, mut mv: Move, chess960: bool) -> Move {
+    if !chess960 && board.piece_on(mv.from) == Some(Piece::King) {
         mv.to = match (mv.from, mv.to) {
             (Square::E1, Square::H1) => Square::G1,
             (Square::E8, Square::H8) => Square::G8,
@@ -367,131 +620,192 @@ pub fn reverse_castling_move(board: &Board, mut mv: Move) -> Move {
     mv
 }
 
-fn read_input() -> Result<String, ()> {
-    let mut line = String::new();
-    let bytes_read = std::io::stdin().read_line(&mut line).unwrap();
+// Chess960 FENs come in two flavours, Shredder-FEN with the rook files as castling rights
+// and X-FEN which keeps KQkq for the outermost rooks. Both are turned into Shredder-FEN.
+pub fn parse_fen(fen: &str, chess960: bool) -> Result<Board, FenParseError> {
+    if !chess960 {
+        return Board::from_fen(fen, false);
+    }
 
-    if bytes_read == 0 {
-        // EOF
-        return Err(());
+    let mut fields: Vec<String> = fen.split_whitespace().map(String::from).collect();
+    if fields.len() < 3 {
+        return Board::from_fen(fen, true);
     }
 
-    Ok(line.trim().to_string())
+    // The pieces are needed to find the rooks, so parse them with empty castling rights
+    let mut stripped = fields.clone();
+    stripped[2] = "-".to_string();
+    let Ok(pieces) = Board::from_fen(&stripped.join(" "), true) else {
+        return Board::from_fen(fen, true);
+    };
+
+    let rights: String = fields[2]
+        .chars()
+        .map(|c| {
+            let color = if c.is_ascii_uppercase() {
+                Color::White
+            } else {
+                Color::Black
+            };
+            let short = match c.to_ascii_lowercase() {
+                'k' => true,
+                'q' => false,
+                _ => return c,
+            };
+
+            let king = pieces.king(color);
+            let rooks = pieces.colored_pieces(color, Piece::Rook)
+                & Rank::First.relative_to(color).bitboard();
+            let rook = if short {
+                rooks.into_iter().filter(|sq| sq.file() > king.file()).max()
+            } else {
+                rooks.into_iter().filter(|sq| sq.file() < king.file()).min()
+            };
+
+            match rook {
+                Some(sq) if color == Color::White => char::from(sq.file()).to_ascii_uppercase(),
+                Some(sq) => char::from(sq.file()),
+                None => c,
+            }
+        })
+        .collect();
+
+    fields[2] = rights;
+    Board::from_fen(&fields.join(" "), true)
 }
 
-#[allow(clippy::borrowed_box, clippy::too_many_arguments)]
-fn go(
-    board: &Board,
-    st: SearchType,
-    tt: &mut TT,
-    nnue: &Box<NNUEState>,
-    history: &mut History,
-    game_history: &Vec<u64>,
-    stored_input: &mut Option<String>,
-    uci_options: &UCIOptions,
-) {
-    let mut search = Search::new(tt, nnue, history, game_history);
-    let mut secondary_searchers = vec![];
+fn parse_limits(tokens: &Tokens, stm: Color) -> Result<SearchLimits, UciError> {
+    let (time, inc) = match stm {
+        Color::White => ("wtime", "winc"),
+        Color::Black => ("btime", "binc"),
+    };
 
-    for _ in 0..uci_options.threads - 1 {
-        secondary_searchers.push(Search::new(tt, nnue, history, game_history));
+    // A clock for the other side only is most likely a GUI mixup, better not guess
+    if !tokens.contains(time) && (tokens.contains("wtime") || tokens.contains("btime")) {
+        return Err(UciError::MissingValue(time.to_string()));
     }
 
-    std::thread::scope(|s| {
-        s.spawn(|| {
-            search.iterative_deepening::<true>(board, st, false);
-        });
+    let clock = match tokens.value::<u64>(time)? {
+        Some(time) => Some(Clock {
+            time,
+            inc: tokens.value(inc)?.unwrap_or(0),
+            moves_to_go: tokens.value("movestogo")?,
+        }),
+        None => None,
+    };
+
+    let mut limits = SearchLimits {
+        depth: tokens.value("depth")?,
+        nodes: tokens.value("nodes")?,
+        movetime: tokens.value("movetime")?,
+        clock,
+        mate: tokens.value("mate")?,
+        infinite: tokens.contains("infinite"),
+    };
+
+    // A plain "go" searches until stopped
+    if limits == SearchLimits::default() {
+        limits.infinite = true;
+    }
+
+    Ok(limits)
+}
 
-        for searcher in secondary_searchers.iter_mut() {
-            s.spawn(|| {
-                searcher.iterative_deepening::<false>(board, st, false);
+// The words that may follow "go", and so end the list of searchmoves
+const GO_PARAMETERS: [&str; 11] = [
+    "wtime",
+    "btime",
+    "winc",
+    "binc",
+    "movestogo",
+    "depth",
+    "nodes",
+    "mate",
+    "movetime",
+    "infinite",
+    "ponder",
+];
+
+// Collects the moves following "searchmoves", up to the next go parameter. A move we
+// can't play is an error, dropping it could leave nothing and so search every move.
+fn parse_search_moves(
+    board: &Board,
+    tokens: &Tokens,
+    chess960: bool,
+) -> Result<Vec<Move>, UciError> {
+    let mut moves = vec![];
+    let Some(words) = tokens.after("searchmoves") else {
+        return Ok(moves);
+    };
+
+    for &word in words
+        .iter()
+        .take_while(|word| !GO_PARAMETERS.contains(word))
+    {
+        let mv = word
+            .parse::<Move>()
+            .map_err(|_| UciError::InvalidMove(word.to_string()))?;
+
+        let mv = check_castling_move(board, mv, chess960);
+        if !board.is_legal(mv) {
+            return Err(UciError::IllegalMove {
+                mv: word.to_string(),
+                fen: board.to_string(),
             });
         }
 
-        *stored_input = handle_stop_and_quit();
-    });
+        if !moves.contains(&mv) {
+            moves.push(mv);
+        }
+    }
 
-    *history = search.info.history;
-    history.age_table();
-    tt.age();
+    if moves.is_empty() {
+        return Err(UciError::MissingValue("searchmoves".to_string()));
+    }
 
-    store_stop(false);
-    clear_nodes();
+    Ok(moves)
 }
 
-fn handle_stop_and_quit() -> Option<String> {
-    loop {
-        let line = read_input().unwrap();
+fn read_input() -> Result<String, ()> {
+    let mut line = String::new();
 
-        match line.as_str().trim() {
-            "stop" => {
-                crate::body::search::store_stop(true);
-                return None;
-            }
-            "quit" => std::process::exit(0),
-            "isready" => println!("readyok"),
-            _ => {
-                if crate::body::search::load_stop() {
-                    return Some(line);
-                }
-            }
-        }
+    match std::io::stdin().read_line(&mut line) {
+        // EOF
+        Ok(0) => Err(()),
+        Ok(_) => Ok(line.trim().to_string()),
+        // The line wasn't UTF-8, skip it
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => Ok(String::new()),
+        Err(_) => Err(()),
     }
 }
 
-fn set_position(
-    board: &mut Board,
-    nnue: &mut NNUEState,
-    game_history: &mut Vec<u64>,
-    board_set: &mut bool,
-    words: Vec<&str>,
-) {
-    if words[1] == "startpos" {
-        *board = Board::default();
-        *board_set = true;
-        *game_history = vec![board.hash()]
-    } else if words[1] == "fen" {
-        // Put together the split fen string
-        let mut fen = String::new();
-        for word in words.iter().skip(2) {
-            if *word == "moves" {
-                break;
-            }
-            fen.push_str(word);
-            fen.push(' ');
-        }
+// Reads stdin on its own thread, so commands keep coming in while searching
+fn spawn_input_thread() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
 
-        if let Ok(b) = Board::from_fen(fen.trim(), false) {
-            *board = b;
-            *board_set = true;
-            *game_history = vec![board.hash()]
-        }
-    }
+    std::thread::spawn(move || loop {
+        // Treat EOF like quit
+        let line = read_input().unwrap_or_else(|()| "quit".to_string());
+        let eof = line == "quit";
 
-    if words.iter().any(|&x| x == "moves") && *board_set {
-        for word in words
-            .iter()
-            .skip(words.iter().position(|&x| x == "moves").unwrap() + 1)
-        {
-            let mut mv: Move = word.parse().unwrap();
-            mv = check_castling_move(board, mv);
-            board.play_unchecked(mv);
-            game_history.push(board.hash());
+        if sender.send(line).is_err() || eof {
+            break;
         }
-    }
+    });
 
-    if *board_set {
-        nnue.refresh(board);
-    }
+    receiver
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn pretty_print(
     depth: usize,
     seldepth: usize,
     score: i32,
+    bound: TTFlag,
     nodes: u64,
     timer: u128,
     pv: String,
+    multipv: Option<usize>,
 ) {
     const DEFAULT: &str = "\x1b[0m";
     const GREY: &str = "\x1b[90m";
@@ -537,6 +851,13 @@ pub fn pretty_print(
         _ => unreachable!(),
     };
 
+    // A failed aspiration window, the score is only a bound
+    let sc = match bound {
+        TTFlag::LowerBound => format!("{sc}{GREY}↑{DEFAULT}"),
+        TTFlag::UpperBound => format!("{sc}{GREY}↓{DEFAULT}"),
+        _ => sc,
+    };
+
     let d = format!("{}/{}", depth, seldepth);
 
     let timer = timer.max(1);
@@ -561,5 +882,574 @@ pub fn pretty_print(
         str.to_string()
     };
 
-    println!("{d: <7} {sc: <8} {n: <8} {knps: <18} {t: <15} {pv}");
+    // Only number the lines when there is more than one
+    let line = match multipv {
+        Some(k) => format!("{GREY}{k}.{DEFAULT}"),
+        None => String::new(),
+    };
+
+    println!("{d: <7} {sc: <8} {n: <8} {knps: <18} {t: <15} {line}{pv}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn chess960_fens() {
+        // X-FEN castling rights refer to the outermost rooks
+        let board = parse_fen(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+            true,
+        )
+        .unwrap();
+        assert_eq!(board, Board::default());
+
+        let shredder = parse_fen(
+            "1rqbkrbn/1ppppp1p/1n6/p1N3p1/8/2P4P/PP1PPPP1/1RQBKRBN w FBfb - 0 9",
+            true,
+        )
+        .unwrap();
+        let xfen = parse_fen(
+            "1rqbkrbn/1ppppp1p/1n6/p1N3p1/8/2P4P/PP1PPPP1/1RQBKRBN w KQkq - 0 9",
+            true,
+        )
+        .unwrap();
+        assert_eq!(xfen, shredder);
+        assert_eq!(
+            xfen.castle_rights(Color::White).short,
+            Some(cozy_chess::File::F)
+        );
+        assert_eq!(
+            xfen.castle_rights(Color::Black).long,
+            Some(cozy_chess::File::B)
+        );
+
+        // Shredder-FEN is only understood in Chess960 mode
+        assert!(parse_fen(
+            "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w HAha - 0 1",
+            false
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn castling_notation() {
+        let board = Board::default();
+        let standard: Move = "e1g1".parse().unwrap();
+        let internal: Move = "e1h1".parse().unwrap();
+
+        assert_eq!(check_castling_move(&board, standard, false), internal);
+        assert_eq!(reverse_castling_move(&board, internal, false), standard);
+
+        // Chess960 castling is already king takes rook
+        assert_eq!(check_castling_move(&board, internal, true), internal);
+        assert_eq!(reverse_castling_move(&board, internal, true), internal);
+    }
+
+    #[test]
+    fn combined_limits() {
+        let tokens = Tokens::new(
+            "go wtime 60000 btime 30000 winc 1000 binc 500 movestogo 20 depth 20 movetime 5000",
+        );
+
+        let limits = parse_limits(&tokens, Color::Black).unwrap();
+        assert_eq!(limits.depth, Some(20));
+        assert_eq!(limits.movetime, Some(5000));
+        assert_eq!(
+            limits.clock,
+            Some(Clock {
+                time: 30000,
+                inc: 500,
+                moves_to_go: Some(20),
+            })
+        );
+        assert!(!limits.infinite);
+
+        // The tighter time bound wins
+        let options = TimeOptions::default();
+        let (opt, max) = limits.time_bounds(false, &options).unwrap();
+        let (clock_opt, clock_max) = time_for_move(30000, 500, Some(20), &options);
+        assert_eq!((opt, max), (clock_opt.min(5000), clock_max.min(5000)));
+
+        let limits = parse_limits(&Tokens::new("go nodes 1000 mate 3"), Color::White).unwrap();
+        assert_eq!(limits.nodes, Some(1000));
+        assert_eq!(limits.mate, Some(3));
+        assert_eq!(limits.time_bounds(false, &options), None);
+
+        // Nothing to stop at
+        assert_eq!(
+            parse_limits(&Tokens::new("go"), Color::White),
+            Ok(SearchLimits::infinite())
+        );
+        assert!(
+            parse_limits(&Tokens::new("go infinite depth 5"), Color::White)
+                .unwrap()
+                .infinite
+        );
+    }
+
+    #[test]
+    fn illegal_moves() {
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "position startpos moves e2e4 e7e5");
+
+        let board = handler.board.clone();
+        let game_history = handler.game_history.clone();
+        let accumulator = handler.nnue.accumulators[handler.nnue.current_acc];
+
+        let illegal = |mv: &str, fen: &str| {
+            Err(UciError::IllegalMove {
+                mv: mv.to_string(),
+                fen: fen.to_string(),
+            })
+        };
+
+        // Wrong side, blocked pawn, and castling through pieces
+        assert_eq!(
+            handler.execute("position startpos moves e2e4 e2e4"),
+            illegal(
+                "e2e4",
+                "rnbqkbnr/pppppppp/8/8/4P3/8/PPPP1PPP/RNBQKBNR b KQkq e3 0 1"
+            )
+        );
+        assert_eq!(
+            handler.execute("position startpos moves e2e4 e7e5 e4e5"),
+            illegal(
+                "e4e5",
+                "rnbqkbnr/pppp1ppp/8/4p3/4P3/8/PPPP1PPP/RNBQKBNR w KQkq e6 0 2"
+            )
+        );
+        assert_eq!(
+            handler.execute("position startpos moves e1g1"),
+            illegal("e1g1", &Board::default().to_string())
+        );
+
+        // No castling rights left for e1g1 to turn into a castling move
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w kq - 0 1";
+        assert_eq!(
+            handler.execute(&format!("position fen {fen} moves e1g1")),
+            illegal("e1g1", fen)
+        );
+
+        // The previous position is left untouched
+        assert_eq!(handler.board, board);
+        assert_eq!(handler.game_history, game_history);
+        assert_eq!(
+            handler.nnue.accumulators[handler.nnue.current_acc],
+            accumulator
+        );
+
+        // ... while the legal versions go through
+        run(
+            &mut handler,
+            "position fen r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1 moves e1g1 e8c8",
+        );
+        assert_eq!(
+            handler.board.to_string(),
+            "2kr3r/8/8/8/8/8/8/R4RK1 w - - 2 2"
+        );
+
+        // Chess960 castling is king takes rook, so the standard notation is illegal there
+        run(&mut handler, "setoption name UCI_Chess960 value true");
+        let fen = "r3k2r/8/8/8/8/8/8/R3K2R w HAha - 0 1";
+        assert_eq!(
+            handler.execute(&format!("position fen {fen} moves e1g1")),
+            illegal("e1g1", "r3k2r/8/8/8/8/8/8/R3K2R w KQkq - 0 1")
+        );
+        run(&mut handler, &format!("position fen {fen} moves e1h1"));
+        assert_eq!(handler.game_history.len(), 2);
+    }
+
+    #[test]
+    fn search_moves() {
+        let board = Board::default();
+        let moves = |line: &str| parse_search_moves(&board, &Tokens::new(line), false);
+        let parsed = |mvs: &[&str]| Ok(mvs.iter().map(|mv| mv.parse().unwrap()).collect());
+
+        assert_eq!(moves("go depth 5"), Ok(vec![]));
+        assert_eq!(
+            moves("go searchmoves e2e4 d2d4 e2e4 depth 5"),
+            parsed(&["e2e4", "d2d4"])
+        );
+        assert_eq!(moves("go depth 5 searchmoves g1f3"), parsed(&["g1f3"]));
+
+        // Leaving out what we can't play would search everything instead
+        assert_eq!(
+            moves("go searchmoves e2e5 depth 5"),
+            Err(UciError::IllegalMove {
+                mv: "e2e5".to_string(),
+                fen: board.to_string()
+            })
+        );
+        assert_eq!(
+            moves("go searchmoves e2e4 knight"),
+            Err(UciError::InvalidMove("knight".to_string()))
+        );
+        assert_eq!(
+            moves("go searchmoves infinite"),
+            Err(UciError::MissingValue("searchmoves".to_string()))
+        );
+
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "position startpos");
+        assert!(handler.execute("go infinite searchmoves e2e5").is_err());
+        assert!(!handler.pool.is_searching());
+    }
+
+    #[test]
+    fn malformed_commands() {
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+
+        let missing = |name: &str| Err(UciError::MissingValue(name.to_string()));
+        let invalid = |name: &str, value: &str| {
+            Err(UciError::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+        };
+
+        assert_eq!(handler.execute("setoption"), missing("name"));
+        assert_eq!(handler.execute("setoption name Hash"), missing("Hash"));
+        assert_eq!(
+            handler.execute("setoption name Hash value 0"),
+            invalid("Hash", "0")
+        );
+        assert_eq!(
+            handler.execute("setoption name Threads value many"),
+            invalid("Threads", "many")
+        );
+        assert_eq!(
+            handler.execute("setoption name Style value Risky"),
+            Err(UciError::UnknownOption("Style".to_string()))
+        );
+        assert_eq!(handler.execute("position"), missing("position"));
+        assert_eq!(
+            handler.execute("position fen"),
+            Err(UciError::InvalidFen(String::new()))
+        );
+        assert_eq!(
+            handler.execute("position startpos moves e2e4 e7"),
+            Err(UciError::InvalidMove("e7".to_string()))
+        );
+        assert_eq!(handler.execute("go perft"), missing("perft"));
+        assert_eq!(
+            handler.execute("xyzzy"),
+            Err(UciError::UnknownCommand("xyzzy".to_string()))
+        );
+
+        // The position wasn't changed by any of the above
+        assert!(!handler.board_set);
+
+        run(&mut handler, "position startpos moves e2e4");
+        assert_eq!(handler.execute("go wtime 1000 winc 10"), missing("btime"));
+        assert_eq!(
+            handler.execute("go btime 1000 binc abc"),
+            invalid("binc", "abc")
+        );
+        assert!(!handler.pool.is_searching());
+
+        // Option names aren't case sensitive
+        run(&mut handler, "setoption name multipv value 3");
+        assert_eq!(handler.uci_options.multipv, 3);
+
+        run(&mut handler, "setoption name Move Overhead value 250");
+        assert_eq!(handler.uci_options.time.move_overhead, 250);
+        assert_eq!(
+            handler.execute("setoption name Maximum Time Scale value 5"),
+            invalid("Maximum Time Scale", "5")
+        );
+
+        run(&mut handler, "setoption name nodestime value 1000");
+        assert_eq!(handler.uci_options.time.nodes_time, 1000);
+        run(&mut handler, "setoption name NodesTime value 0");
+        assert_eq!(handler.uci_options.time.nodes_time, 0);
+
+        // Nothing to play still gets an answer, however the search is started
+        for fen in [CHECKMATE, STALEMATE] {
+            run(&mut handler, &format!("position fen {fen}"));
+            run(&mut handler, "go depth 3");
+            assert_eq!(handler.finish_search().unwrap().best_move, None);
+            run(&mut handler, "go infinite");
+            run(&mut handler, "stop");
+            run(&mut handler, "go ponder wtime 1000 btime 1000");
+            run(&mut handler, "ponderhit");
+            wait(&mut handler);
+            run(&mut handler, "go perft 2");
+            run(&mut handler, "eval");
+            assert!(!handler.pool.is_searching());
+        }
+    }
+
+    #[test]
+    fn hash_allocation() {
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "position startpos");
+        run(&mut handler, "go depth 4");
+        wait(&mut handler);
+
+        // A new game empties the table it already has
+        let tt = Arc::as_ptr(&handler.tt);
+        run(&mut handler, "ucinewgame");
+        assert_eq!(Arc::as_ptr(&handler.tt), tt);
+        assert!(handler.tt.probe(handler.board.hash()).is_none());
+
+        // So does clearing the hash
+        run(&mut handler, "go depth 4");
+        handler.finish_search();
+        assert!(handler.tt.probe(handler.board.hash()).is_some());
+        run(&mut handler, "setoption name Clear Hash");
+        assert_eq!(Arc::as_ptr(&handler.tt), tt);
+        assert!(handler.tt.probe(handler.board.hash()).is_none());
+
+        // Setting the size it already has keeps it and what's in it, another size doesn't
+        run(&mut handler, "go depth 4");
+        handler.finish_search();
+        run(&mut handler, "setoption name Hash value 16");
+        assert_eq!(Arc::as_ptr(&handler.tt), tt);
+        assert!(handler.tt.probe(handler.board.hash()).is_some());
+        run(&mut handler, "setoption name Hash value 32");
+        assert!(handler.tt.probe(handler.board.hash()).is_none());
+    }
+
+    #[test]
+    fn hash_file() {
+        let mut handler = Handler::new();
+        let path = std::env::temp_dir().join(format!("svart handler {}.hash", std::process::id()));
+        let path = path.to_str().unwrap();
+        let hash_file = format!("setoption name Hash File value {path}");
+        run(&mut handler, "uci");
+        run(&mut handler, &hash_file);
+        run(&mut handler, "position startpos");
+        run(&mut handler, "go depth 4");
+        handler.finish_search();
+        let key = handler.board.hash();
+        let entry = handler.tt.probe(key).map(u64::from);
+        assert!(entry.is_some());
+
+        // The work survives a new game, or even a new engine
+        run(&mut handler, "setoption name Save Hash");
+        run(&mut handler, "ucinewgame");
+        assert!(handler.tt.probe(key).is_none());
+        run(&mut handler, "setoption name Load Hash");
+        assert_eq!(handler.tt.probe(key).map(u64::from), entry);
+
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, &hash_file);
+        run(&mut handler, "setoption name Hash value 4");
+        run(&mut handler, "setoption name Load Hash");
+        assert_eq!(handler.tt.probe(key).map(u64::from), entry);
+
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(
+            handler.execute("setoption name Load Hash"),
+            Err(UciError::HashFile { .. })
+        ));
+    }
+
+    #[test]
+    fn reset_sessions() {
+        // Nodes of every search in a short analysis session
+        fn session(handler: &mut Handler) -> Vec<u64> {
+            let searches = [
+                ("position startpos moves e2e4 e7e5", "go depth 6"),
+                ("position startpos moves e2e4 e7e5 g1f3", "go nodes 3000"),
+                ("position startpos moves e2e4 e7e5 g1f3 b8c6", "go depth 5"),
+            ];
+
+            searches
+                .iter()
+                .map(|&(position, go)| {
+                    run(handler, position);
+                    run(handler, go);
+                    handler.finish_search().unwrap().nodes
+                })
+                .collect()
+        }
+
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "ucinewgame");
+        let first = session(&mut handler);
+
+        // What the first session left behind changes the second one
+        let warm = session(&mut handler);
+        assert_ne!(warm, first);
+
+        // Unless a new game wipes all of it, a late stop included
+        run(&mut handler, "stop");
+        assert!(!handler.shared.load_stop());
+        handler.shared.store_stop(true);
+        run(&mut handler, "ucinewgame");
+        assert!(!handler.shared.load_stop());
+        assert_eq!(handler.tt.epoch, 0);
+        assert_eq!(handler.game_history, vec![Board::default().hash()]);
+        assert_eq!(session(&mut handler), first);
+
+        // A brand new engine agrees
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "ucinewgame");
+        assert_eq!(session(&mut handler), first);
+    }
+
+    #[test]
+    fn background_search() {
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "position startpos");
+
+        // An infinite search keeps running until it's told to stop
+        run(&mut handler, "go infinite");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(handler.pool.is_searching());
+
+        run(&mut handler, "stop");
+        assert!(!handler.pool.is_searching());
+        assert!(!handler.shared.load_stop());
+        assert_eq!(Arc::strong_count(&handler.tt), 1);
+
+        // Everything else is run once the search is over
+        run(&mut handler, "go depth 3");
+        run(&mut handler, "eval");
+        wait(&mut handler);
+        assert!(!handler.pool.is_searching());
+
+        // The threads outlive the search, and follow the option
+        run(&mut handler, "setoption name Threads value 3");
+        assert_eq!(handler.pool.threads(), 3);
+        run(&mut handler, "go depth 3");
+        run(&mut handler, "setoption name Threads value 1");
+        wait(&mut handler);
+        assert_eq!(handler.pool.threads(), 1);
+
+        assert_eq!(handler.execute("quit"), Ok(ControlFlow::Break(())));
+    }
+
+    #[test]
+    fn commands_during_search() {
+        let mut handler = Handler::new();
+        run(&mut handler, "uci");
+        run(&mut handler, "position startpos");
+
+        // An unbounded search doesn't hold up the input, the rest waits its turn
+        run(&mut handler, "go infinite");
+        run(&mut handler, "setoption name Hash value 32");
+        run(&mut handler, "isready");
+        assert!(handler.pool.is_searching());
+        assert_eq!(handler.uci_options.hash, 16);
+
+        run(&mut handler, "stop");
+        assert!(!handler.pool.is_searching());
+        assert!(!handler.has_queued());
+        assert_eq!(handler.uci_options.hash, 32);
+
+        // Stop and ponderhit are meant for the last search asked for, so they wait behind
+        // one still queued. The long search ending here stands in for one of any length.
+        for (go, control) in [("go infinite", "stop"), ("go ponder", "ponderhit")] {
+            run(&mut handler, "go movetime 100000");
+            run(&mut handler, &format!("{go} wtime 1000 btime 1000"));
+            run(&mut handler, control);
+            assert!(handler.pool.is_searching());
+            assert_eq!(handler.queued.len(), 2);
+
+            handler.shared.store_stop(true);
+            handler.finish_search();
+            assert!(handler.run_queued().is_continue());
+            assert!(!handler.has_queued());
+            assert!(!handler.shared.load_ponder());
+            wait(&mut handler);
+        }
+
+        // In the order they came in, up to the next search. A search which would never end
+        // by itself is stopped rather than holding up everything after it.
+        run(&mut handler, "go infinite");
+        run(&mut handler, "position startpos moves e2e4");
+        run(&mut handler, "go infinite");
+        run(&mut handler, "position startpos moves d2d4");
+        run(&mut handler, "stop");
+        assert!(handler.pool.is_searching());
+        assert_eq!(handler.game_history.len(), 2);
+        assert!(handler.has_queued());
+
+        run(&mut handler, "stop");
+        assert!(!handler.pool.is_searching());
+        assert!(!handler.has_queued());
+
+        // Quit doesn't wait for anything
+        assert_eq!(handler.execute("quit"), Ok(ControlFlow::Break(())));
+        assert!(!handler.pool.is_searching());
+    }
+
+    // Lets the searches finish, along with everything queued behind them
+    fn wait(handler: &mut Handler) {
+        while handler.pool.is_searching() || handler.has_queued() {
+            handler.finish_search();
+            assert!(handler.run_queued().is_continue());
+        }
+    }
+
+    fn run(handler: &mut Handler, line: &str) {
+        assert_eq!(
+            handler.execute(line),
+            Ok(ControlFlow::Continue(())),
+            "{line}"
+        );
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(200))]
+
+        // Garbage in, info string out, but never a panic
+        #[test]
+        fn random_commands(lines in prop::collection::vec(command_line(), 1..8)) {
+            let mut handler = Handler::new();
+
+            for line in lines {
+                let _ = handler.execute(&line);
+                run(&mut handler, "stop");
+            }
+
+            // Nor in any of the search threads
+            prop_assert_eq!(handler.pool.failures(), 0);
+        }
+    }
+
+    const CHECKMATE: &str = "7k/6Q1/6K1/8/8/8/8/8 b - - 0 1";
+    const STALEMATE: &str = "7k/5Q2/6K1/8/8/8/8/8 b - - 0 1";
+
+    // Mostly UCI words, with a few values which are out of range or make no sense,
+    // and now and then a position without any legal move or a search which works
+    fn command_line() -> impl Strategy<Value = String> {
+        #[rustfmt::skip]
+        const WORDS: [&str; 50] = [
+            "uci", "ucinewgame", "isready", "setoption", "name", "value", "Hash", "Threads",
+            "MultiPV", "Ponder", "UCI_Chess960", "position", "startpos", "fen", "moves", "go",
+            "ponder", "ponderhit", "wtime", "btime", "winc", "binc", "movestogo", "depth", "nodes",
+            "mate", "movetime", "infinite", "searchmoves", "perft", "divide", "eval", "0", "1", "2",
+            "-1", "true", "abc", "9000000000000000000", "18446744073709551615",
+            "18446744073709551616", "8/8/8/8/8/8/8/8", "w", "KQkq", "e2e4", "e7e5", "g1f3", "e1g1",
+            "e1h1", "a7a8q",
+        ];
+
+        let word = prop_oneof![
+            4 => prop::sample::select(&WORDS[..]).prop_map(str::to_string),
+            1 => "[ -~]{0,12}",
+        ];
+        let game_over = prop::sample::select(&[CHECKMATE, STALEMATE][..])
+            .prop_map(|fen| format!("position fen {fen}"));
+        let search = prop::sample::select(&["go depth 2", "go movetime 10", "go infinite"][..])
+            .prop_map(str::to_string);
+        prop_oneof![
+            8 => prop::collection::vec(word, 0..10).prop_map(|words| words.join(" ")),
+            1 => game_over,
+            1 => search,
+        ]
+    }
 }
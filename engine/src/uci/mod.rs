@@ -1,3 +1,5 @@
 pub mod bench;
 pub mod handler;
-mod timeman;
+pub mod parser;
+pub mod reporter;
+pub mod timeman;
@@ -0,0 +1,130 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Everything that can be wrong with a command. The handler reports these
+// as "info string" and carries on, nothing sent by the GUI may crash the engine.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UciError {
+    UnknownCommand(String),
+    // The parameter is there but what should follow it isn't
+    MissingValue(String),
+    InvalidValue { name: String, value: String },
+    UnknownOption(String),
+    InvalidFen(String),
+    InvalidMove(String),
+    IllegalMove { mv: String, fen: String },
+    HashFile { path: String, error: String },
+}
+
+impl fmt::Display for UciError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciError::UnknownCommand(command) => write!(f, "unknown command {command}"),
+            UciError::MissingValue(name) => write!(f, "missing value for {name}"),
+            UciError::InvalidValue { name, value } => {
+                write!(f, "invalid value {value} for {name}")
+            }
+            UciError::UnknownOption(name) => write!(f, "unknown option {name}"),
+            UciError::InvalidFen(fen) => write!(f, "invalid fen {fen}"),
+            UciError::InvalidMove(mv) => write!(f, "invalid move {mv}"),
+            UciError::IllegalMove { mv, fen } => write!(f, "illegal move {mv} in {fen}"),
+            UciError::HashFile { path, error } => write!(f, "hash file {path}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for UciError {}
+
+// A command line split into words. UCI is mostly made of "name value" pairs,
+// so most lookups go by the word in front of what we're after.
+pub struct Tokens<'a> {
+    words: Vec<&'a str>,
+}
+
+impl<'a> Tokens<'a> {
+    pub fn new(line: &'a str) -> Self {
+        Tokens {
+            words: line.split_whitespace().collect(),
+        }
+    }
+
+    pub fn command(&self) -> Option<&'a str> {
+        self.words.first().copied()
+    }
+
+    pub fn contains(&self, word: &str) -> bool {
+        self.words.contains(&word)
+    }
+
+    // The words after the command
+    pub fn args(&self) -> &[&'a str] {
+        self.words.get(1..).unwrap_or_default()
+    }
+
+    // Everything after the first occurrence of `name`
+    pub fn after(&self, name: &str) -> Option<&[&'a str]> {
+        let index = self.words.iter().position(|&x| x == name)?;
+        Some(&self.words[index + 1..])
+    }
+
+    // Everything between `start` and `end`, or up to the end of the line
+    pub fn between(&self, start: &str, end: &str) -> Option<&[&'a str]> {
+        let words = self.after(start)?;
+        let len = words.iter().position(|&x| x == end).unwrap_or(words.len());
+        Some(&words[..len])
+    }
+
+    // Ok(None) if `name` isn't there at all
+    pub fn value<T: FromStr>(&self, name: &str) -> Result<Option<T>, UciError> {
+        let Some(words) = self.after(name) else {
+            return Ok(None);
+        };
+
+        let value = words
+            .first()
+            .ok_or_else(|| UciError::MissingValue(name.to_string()))?;
+        value
+            .parse::<T>()
+            .map(Some)
+            .map_err(|_| UciError::InvalidValue {
+                name: name.to_string(),
+                value: value.to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens() {
+        let tokens = Tokens::new("  setoption name Clear Hash  ");
+        assert_eq!(tokens.command(), Some("setoption"));
+        assert_eq!(tokens.args(), ["name", "Clear", "Hash"]);
+        assert_eq!(
+            tokens.between("name", "value"),
+            Some(&["Clear", "Hash"][..])
+        );
+        assert_eq!(tokens.between("value", "name"), None);
+
+        let tokens = Tokens::new("go wtime 1000 winc abc depth");
+        assert_eq!(tokens.value::<u64>("wtime"), Ok(Some(1000)));
+        assert_eq!(tokens.value::<u64>("btime"), Ok(None));
+        assert_eq!(
+            tokens.value::<u64>("winc"),
+            Err(UciError::InvalidValue {
+                name: "winc".to_string(),
+                value: "abc".to_string()
+            })
+        );
+        assert_eq!(
+            tokens.value::<usize>("depth"),
+            Err(UciError::MissingValue("depth".to_string()))
+        );
+
+        let tokens = Tokens::new("");
+        assert_eq!(tokens.command(), None);
+        assert!(tokens.args().is_empty());
+    }
+}
@@ -0,0 +1,163 @@
+use super::handler::pretty_print;
+use crate::body::{search::format_score, tt::TTFlag};
+
+use cozy_chess::Move;
+
+// Everything known about one PV line once an iteration has been searched.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IterationReport {
+    pub depth: usize,
+    pub seldepth: usize,
+    // 1-indexed MultiPV line
+    pub multipv: usize,
+    pub score: i32,
+    pub bound: TTFlag,
+    pub nodes: u64,
+    // milliseconds
+    pub time: u128,
+    // permille of the TT in use by this search
+    pub hashfull: usize,
+    // in UCI notation
+    pub pv: Vec<Move>,
+}
+
+// Receives the search output which would otherwise go straight to stdout.
+pub trait Reporter {
+    fn iteration(&mut self, report: &IterationReport);
+
+    // The root move about to be searched, 1-indexed `number` in the order they're searched
+    fn current_move(&mut self, depth: usize, mv: Move, number: usize);
+
+    // No move when the side to move is checkmated or stalemated
+    fn best_move(&mut self, best_move: Option<Move>, ponder: Option<Move>);
+}
+
+impl<R: Reporter + ?Sized> Reporter for &mut R {
+    fn iteration(&mut self, report: &IterationReport) {
+        (**self).iteration(report);
+    }
+
+    fn current_move(&mut self, depth: usize, mv: Move, number: usize) {
+        (**self).current_move(depth, mv, number);
+    }
+
+    fn best_move(&mut self, best_move: Option<Move>, ponder: Option<Move>) {
+        (**self).best_move(best_move, ponder);
+    }
+}
+
+fn pv_string(pv: &[Move]) -> String {
+    let mut s = String::new();
+    for mv in pv {
+        s.push(' ');
+        s.push_str(mv.to_string().as_str());
+    }
+
+    s
+}
+
+fn print_best_move(best_move: Option<Move>, ponder: Option<Move>) {
+    match (best_move, ponder) {
+        (Some(best_move), Some(ponder)) => println!("bestmove {best_move} ponder {ponder}"),
+        (Some(best_move), None) => println!("bestmove {best_move}"),
+        // The null move, as UCI has it
+        (None, _) => println!("bestmove 0000"),
+    }
+}
+
+pub struct UciReporter;
+
+impl Reporter for UciReporter {
+    fn iteration(&mut self, report: &IterationReport) {
+        let bound = match report.bound {
+            TTFlag::LowerBound => " lowerbound",
+            TTFlag::UpperBound => " upperbound",
+            _ => "",
+        };
+
+        println!(
+            "info depth {} seldepth {} multipv {} score {}{} nodes {} time {} nps {} hashfull {} pv{}",
+            report.depth,
+            report.seldepth,
+            report.multipv,
+            format_score(report.score),
+            bound,
+            report.nodes,
+            report.time,
+            report.nodes * 1000 / (report.time as u64).max(1),
+            report.hashfull,
+            pv_string(&report.pv)
+        );
+    }
+
+    fn current_move(&mut self, depth: usize, mv: Move, number: usize) {
+        println!("info depth {depth} currmove {mv} currmovenumber {number}");
+    }
+
+    fn best_move(&mut self, best_move: Option<Move>, ponder: Option<Move>) {
+        print_best_move(best_move, ponder);
+    }
+}
+
+pub struct PrettyReporter {
+    // Lines are only numbered when there is more than one
+    pub multipv: bool,
+}
+
+impl Reporter for PrettyReporter {
+    fn iteration(&mut self, report: &IterationReport) {
+        pretty_print(
+            report.depth,
+            report.seldepth,
+            report.score,
+            report.bound,
+            report.nodes,
+            report.time,
+            pv_string(&report.pv),
+            self.multipv.then_some(report.multipv),
+        );
+    }
+
+    // Would only clutter the table
+    fn current_move(&mut self, _: usize, _: Move, _: usize) {}
+
+    fn best_move(&mut self, best_move: Option<Move>, ponder: Option<Move>) {
+        print_best_move(best_move, ponder);
+    }
+}
+
+// Swallows all output, for callers which only want the search result.
+pub struct SilentReporter;
+
+impl Reporter for SilentReporter {
+    fn iteration(&mut self, _: &IterationReport) {}
+
+    fn current_move(&mut self, _: usize, _: Move, _: usize) {}
+
+    fn best_move(&mut self, _: Option<Move>, _: Option<Move>) {}
+}
+
+// Keeps every report around, for tests and for embedding the engine.
+#[derive(Default)]
+pub struct CollectingReporter {
+    pub iterations: Vec<IterationReport>,
+    // (depth, move, number)
+    pub current_moves: Vec<(usize, Move, usize)>,
+    pub best_move: Option<Move>,
+    pub ponder: Option<Move>,
+}
+
+impl Reporter for CollectingReporter {
+    fn iteration(&mut self, report: &IterationReport) {
+        self.iterations.push(report.clone());
+    }
+
+    fn current_move(&mut self, depth: usize, mv: Move, number: usize) {
+        self.current_moves.push((depth, mv, number));
+    }
+
+    fn best_move(&mut self, best_move: Option<Move>, ponder: Option<Move>) {
+        self.best_move = best_move;
+        self.ponder = ponder;
+    }
+}
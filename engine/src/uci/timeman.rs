@@ -1,11 +1,195 @@
 use crate::definitions::TIME_OVERHEAD;
 
+use cozy_chess::Move;
+use std::sync::Arc;
+use std::time::Instant;
+
+// Indexed by the amount of iterations in a row with the same best move
+const STABILITY_FACTOR: [f64; 5] = [2.0, 1.4, 1.1, 0.9, 0.8];
+// Per centipawn the score dropped since the previous iteration
+const SCORE_DROP_FACTOR: f64 = 0.01;
+const FAIL_LOW_FACTOR: f64 = 0.2;
+
+// The time management UCI options, and which soft time adjustments to make
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct TimeOptions {
+    // Milliseconds kept back every move for communication delays
+    pub move_overhead: u64,
+    // Percentages applied to the optimum and maximum time
+    pub optimum_scale: u64,
+    pub maximum_scale: u64,
+    // Nodes counted as a millisecond instead of the wall clock, 0 when off
+    pub nodes_time: u64,
+    // Less time when the best move got most of the nodes
+    pub node_fraction: bool,
+    // Less time when the best move stays the same iteration after iteration
+    pub best_move_stability: bool,
+    // More time when the score drops
+    pub score_trend: bool,
+    // More time when the root aspiration window failed low
+    pub fail_lows: bool,
+}
+
+impl Default for TimeOptions {
+    fn default() -> Self {
+        Self {
+            move_overhead: TIME_OVERHEAD,
+            optimum_scale: 100,
+            maximum_scale: 100,
+            nodes_time: 0,
+            node_fraction: true,
+            best_move_stability: true,
+            score_trend: true,
+            fail_lows: true,
+        }
+    }
+}
+
+impl TimeOptions {
+    // The clock a search with these options should run on
+    pub fn clock(&self) -> Arc<dyn TimeSource> {
+        match self.nodes_time {
+            0 => Arc::new(WallClock::new()),
+            nodes_per_ms => Arc::new(NodeClock::new(nodes_per_ms)),
+        }
+    }
+}
+
+// Where the search reads the time from, in milliseconds since some fixed point.
+// `nodes` are those of all threads so far, for clocks that don't follow the wall.
+pub trait TimeSource: Send + Sync {
+    fn now(&self, nodes: u64) -> u64;
+
+    // How many nodes a thread searches between two readings, the wall isn't free to read
+    fn interval(&self) -> u64 {
+        1024
+    }
+}
+
+pub struct WallClock {
+    start: Instant,
+}
+
+impl WallClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for WallClock {
+    fn now(&self, _nodes: u64) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
+
+// Lets a millisecond pass every `nodes_per_ms` nodes, so the same search always stops at the same point
+pub struct NodeClock {
+    nodes_per_ms: u64,
+}
+
+impl NodeClock {
+    pub fn new(nodes_per_ms: u64) -> Self {
+        Self {
+            nodes_per_ms: nodes_per_ms.max(1),
+        }
+    }
+}
+
+impl TimeSource for NodeClock {
+    fn now(&self, nodes: u64) -> u64 {
+        nodes / self.nodes_per_ms
+    }
+
+    // Every node, so the threads stop as soon as their nodes together use up the time
+    fn interval(&self) -> u64 {
+        1
+    }
+}
+
+// Moves the optimum time around after each iteration, depending on how settled the search looks
+pub struct SoftTime {
+    options: TimeOptions,
+    base_optimum: u64,
+    max: u64,
+    best_move: Option<Move>,
+    stability: usize,
+    prev_score: Option<i32>,
+}
+
+impl SoftTime {
+    pub fn new(base_optimum: u64, max: u64, options: TimeOptions) -> Self {
+        Self {
+            options,
+            base_optimum,
+            max,
+            best_move: None,
+            stability: 0,
+            prev_score: None,
+        }
+    }
+
+    // The optimum time after a finished iteration, never beyond the max time
+    #[must_use]
+    pub fn optimum(
+        &mut self,
+        best_move: Move,
+        score: i32,
+        best_move_fraction: f64,
+        fail_lows: usize,
+    ) -> u64 {
+        if self.best_move == Some(best_move) {
+            self.stability = (self.stability + 1).min(STABILITY_FACTOR.len() - 1);
+        } else {
+            self.stability = 0;
+        }
+        self.best_move = Some(best_move);
+
+        let score_drop = self.prev_score.map_or(0, |prev| prev - score);
+        self.prev_score = Some(score);
+
+        let mut factor = 1.;
+
+        if self.options.node_fraction {
+            factor *= (1.5 - best_move_fraction) * 1.35;
+        }
+
+        if self.options.best_move_stability {
+            factor *= STABILITY_FACTOR[self.stability];
+        }
+
+        if self.options.score_trend {
+            factor *= (1. + score_drop as f64 * SCORE_DROP_FACTOR).clamp(0.75, 1.5);
+        }
+
+        if self.options.fail_lows {
+            factor *= (1. + fail_lows as f64 * FAIL_LOW_FACTOR).min(2.);
+        }
+
+        ((self.base_optimum as f64 * factor) as u64).min(self.max)
+    }
+}
+
 #[must_use]
-pub fn time_for_move(time: u64, inc: u64, moves_to_go: Option<u8>) -> (u64, u64) {
-    // Accounting for overhead
-    let time = time - TIME_OVERHEAD;
+pub fn time_for_move(
+    time: u64,
+    inc: u64,
+    moves_to_go: Option<u8>,
+    options: &TimeOptions,
+) -> (u64, u64) {
+    // Accounting for overhead, a clock below it leaves nothing to think with
+    let time = time.saturating_sub(options.move_overhead);
     let opt_time: f64;
     let max_time: f64;
+    let opt_scale = options.optimum_scale as f64 / 100.;
+    let max_scale = options.maximum_scale as f64 / 100.;
 
     // repeating TC
     if let Some(mtg) = moves_to_go {
@@ -13,16 +197,161 @@ pub fn time_for_move(time: u64, inc: u64, moves_to_go: Option<u8>) -> (u64, u64)
         let scale = 0.7 / f64::from(mtg);
         let eight = 0.8 * time as f64;
 
-        opt_time = (scale * time as f64).min(eight);
-        max_time = (5. * opt_time).min(eight);
+        opt_time = (scale * time as f64 * opt_scale).min(eight);
+        max_time = (5. * scale * time as f64 * max_scale).min(eight);
     // normal TC
     } else {
-        let temp = ((time / 20) + (inc * 3 / 4)) as f64;
-        opt_time = 0.6 * temp;
-        max_time = (temp * 2.).min(time as f64);
+        // Any number the GUI sends has to fit
+        let temp = (time / 20).saturating_add(inc.saturating_mul(3) / 4) as f64;
+        opt_time = 0.6 * temp * opt_scale;
+        max_time = (temp * 2. * max_scale).min(time as f64);
     }
 
+    // Scaling up the optimum may not take it past the maximum
+    let opt_time = opt_time.min(max_time);
+
     // The optimum time is used right after a depth is cleared in the ID loop.
     // Max time is used in the search function as usual for a global stop light.
     (opt_time as u64, max_time as u64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn time_options() {
+        let default = TimeOptions::default();
+        assert_eq!(time_for_move(10_005, 0, None, &default), (300, 1000));
+
+        // The overhead comes off the clock first
+        let overhead = TimeOptions {
+            move_overhead: 1005,
+            ..default
+        };
+        assert_eq!(time_for_move(10_005, 0, None, &overhead), (270, 900));
+
+        let scaled = TimeOptions {
+            optimum_scale: 50,
+            maximum_scale: 300,
+            ..default
+        };
+        assert_eq!(time_for_move(10_005, 0, None, &scaled), (150, 3000));
+
+        // Neither scale may go beyond what's on the clock
+        let greedy = TimeOptions {
+            optimum_scale: 1000,
+            maximum_scale: 1000,
+            ..default
+        };
+        let (opt, max) = time_for_move(10_005, 0, Some(1), &greedy);
+        assert!(opt <= max && max <= 8000);
+        let (opt, max) = time_for_move(10_005, 0, None, &greedy);
+        assert!(opt <= max && max <= 10_000);
+    }
+
+    #[test]
+    fn nodes_time() {
+        let options = TimeOptions {
+            nodes_time: 50,
+            ..TimeOptions::default()
+        };
+        assert_eq!(options.clock().now(0), 0);
+        assert_eq!(options.clock().now(1049), 20);
+
+        // Without nodestime a pile of nodes takes no time at all
+        assert!(TimeOptions::default().clock().now(1_000_000_000) < 1000);
+    }
+
+    #[test]
+    fn clock_below_overhead() {
+        let default = TimeOptions::default();
+        assert_eq!(time_for_move(3, 0, None, &default), (0, 0));
+        assert_eq!(time_for_move(TIME_OVERHEAD, 0, Some(10), &default), (0, 0));
+        // The increment only arrives after the move
+        assert_eq!(time_for_move(0, 1000, None, &default), (0, 0));
+    }
+
+    #[test]
+    fn huge_clock() {
+        let default = TimeOptions::default();
+        let (opt, max) = time_for_move(100_000, 9_000_000_000_000_000_000, None, &default);
+        assert!(opt <= max && max <= 100_000);
+        let (opt, max) = time_for_move(u64::MAX, u64::MAX, None, &default);
+        assert!(opt <= max && max > 0);
+    }
+
+    fn only(f: impl Fn(&mut TimeOptions)) -> TimeOptions {
+        let mut options = TimeOptions {
+            node_fraction: false,
+            best_move_stability: false,
+            score_trend: false,
+            fail_lows: false,
+            ..TimeOptions::default()
+        };
+        f(&mut options);
+        options
+    }
+
+    #[test]
+    fn soft_time_disabled() {
+        let mut soft = SoftTime::new(1000, 5000, only(|_| ()));
+        let e2e4 = "e2e4".parse().unwrap();
+        let d2d4 = "d2d4".parse().unwrap();
+
+        assert_eq!(soft.optimum(e2e4, 50, 0.1, 0), 1000);
+        assert_eq!(soft.optimum(d2d4, -300, 0.9, 3), 1000);
+    }
+
+    #[test]
+    fn best_move_stability() {
+        let mut soft = SoftTime::new(1000, 5000, only(|o| o.best_move_stability = true));
+        let e2e4 = "e2e4".parse().unwrap();
+        let d2d4 = "d2d4".parse().unwrap();
+
+        // A new best move asks for more time, which shrinks while it stays the best
+        let mut prev = soft.optimum(e2e4, 0, 0.5, 0);
+        assert!(prev > 1000);
+        for _ in 0..6 {
+            let opt = soft.optimum(e2e4, 0, 0.5, 0);
+            assert!(opt <= prev);
+            prev = opt;
+        }
+        assert!(prev < 1000);
+
+        assert!(soft.optimum(d2d4, 0, 0.5, 0) > 1000);
+    }
+
+    #[test]
+    fn score_trend() {
+        let mut soft = SoftTime::new(1000, 5000, only(|o| o.score_trend = true));
+        let e2e4 = "e2e4".parse().unwrap();
+
+        assert_eq!(soft.optimum(e2e4, 100, 0.5, 0), 1000);
+        // Dropping by 30cp, then all the way to the cap
+        assert_eq!(soft.optimum(e2e4, 70, 0.5, 0), 1300);
+        assert_eq!(soft.optimum(e2e4, -500, 0.5, 0), 1500);
+        // Rising scores take a little off
+        assert_eq!(soft.optimum(e2e4, 0, 0.5, 0), 750);
+    }
+
+    #[test]
+    fn fail_lows() {
+        let mut soft = SoftTime::new(1000, 5000, only(|o| o.fail_lows = true));
+        let e2e4 = "e2e4".parse().unwrap();
+
+        assert_eq!(soft.optimum(e2e4, 0, 0.5, 0), 1000);
+        assert_eq!(soft.optimum(e2e4, 0, 0.5, 2), 1400);
+        assert_eq!(soft.optimum(e2e4, 0, 0.5, 10), 2000);
+    }
+
+    #[test]
+    fn soft_time_within_max() {
+        let mut soft = SoftTime::new(1000, 3000, TimeOptions::default());
+        let e2e4 = "e2e4".parse().unwrap();
+
+        // Everything pointing at more time still stays below the max
+        assert_eq!(soft.optimum(e2e4, 0, 0., 0), 3000);
+        assert_eq!(soft.optimum(e2e4, -1000, 0., 10), 3000);
+    }
+}